@@ -0,0 +1,29 @@
+// Names the build by a hash of the source it's built from, for the JIT's
+// cache on disk: code a build cached is only reused by a build of the same
+// source, even when the version number hasn't changed.
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hasher;
+use std::path::Path;
+
+fn hash_dir(dir: &Path, hasher: &mut DefaultHasher) {
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            hash_dir(&path, hasher);
+        } else {
+            hasher.write(path.to_string_lossy().as_bytes());
+            hasher.write(&fs::read(&path).unwrap());
+        }
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    let mut hasher = DefaultHasher::new();
+    hash_dir(Path::new("src"), &mut hasher);
+    hasher.write(&fs::read("Cargo.toml").unwrap());
+    println!("cargo:rustc-env=BUILD_ID={:016x}", hasher.finish());
+}
@@ -0,0 +1,222 @@
+use std::ptr;
+use std::slice;
+
+// Code is allocated from regions this big, so small functions share pages.
+// Anything bigger gets a region of its own.
+const REGION_SIZE: usize = 1 << 16;
+const ALIGN: usize = 16;
+
+struct Region {
+    base: *mut u8,
+    size: usize,
+    // (offset, len) of each free block, sorted by offset, none adjacent
+    free: Vec<(usize, usize)>,
+}
+
+// A block of code in the cache. It stays where it is until it's freed.
+pub struct Code {
+    region: usize,
+    offset: usize,
+    len: usize,
+    ptr: *mut u8,
+}
+
+impl Code {
+    pub fn ptr(&self, offset: usize) -> *const u8 {
+        unsafe { self.ptr.add(offset) }
+    }
+}
+
+pub struct Stats {
+    pub regions: usize,
+    pub capacity: usize,
+    pub used: usize,
+    pub blocks: usize,
+    pub free_blocks: usize,
+    pub largest_free: usize,
+    pub evictions: usize,
+}
+
+impl Stats {
+    // How much of the free space is unusable for code as big as all of it.
+    pub fn fragmentation(&self) -> f64 {
+        let free = self.capacity - self.used;
+        if free == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free as f64 / free as f64
+        }
+    }
+
+    pub fn report(&self) {
+        eprintln!(
+            "code cache: {} / {} bytes in {} blocks, {} regions, {} evictions, {} free blocks ({:.1}% fragmented)",
+            self.used,
+            self.capacity,
+            self.blocks,
+            self.regions,
+            self.evictions,
+            self.free_blocks,
+            100.0 * self.fragmentation()
+        );
+    }
+}
+
+// Executable memory for all compiled code, allocated first fit. Regions
+// are writable only while code is copied in or patched, and unmapped once
+// nothing in them is left, leaving their slot to the next new region.
+pub struct CodeCache {
+    regions: Vec<Option<Region>>,
+    limit: Option<usize>,
+    used: usize,
+    blocks: usize,
+    evictions: usize,
+}
+
+impl CodeCache {
+    pub fn new() -> CodeCache {
+        CodeCache {
+            regions: Vec::new(),
+            limit: None,
+            used: 0,
+            blocks: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = Some(limit);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn record_eviction(&mut self) {
+        self.evictions += 1;
+    }
+
+    pub fn insert(&mut self, bytes: &[u8]) -> Code {
+        let len = bytes.len().max(1).div_ceil(ALIGN) * ALIGN;
+        let found = self.regions.iter().enumerate().find_map(|(r, region)| {
+            let region = region.as_ref()?;
+            region.free.iter().position(|&(_, free)| free >= len).map(|k| (r, k))
+        });
+        let (r, k) = match found {
+            Some(found) => found,
+            None => {
+                let region = Some(Region::new(len.max(REGION_SIZE)));
+                match self.regions.iter().position(Option::is_none) {
+                    Some(r) => {
+                        self.regions[r] = region;
+                        (r, 0)
+                    }
+                    None => {
+                        self.regions.push(region);
+                        (self.regions.len() - 1, 0)
+                    }
+                }
+            }
+        };
+        let region = self.regions[r].as_mut().unwrap();
+        let (offset, free) = region.free[k];
+        if free == len {
+            region.free.remove(k);
+        } else {
+            region.free[k] = (offset + len, free - len);
+        }
+        self.used += len;
+        self.blocks += 1;
+        let code = Code {
+            region: r,
+            offset,
+            len,
+            ptr: unsafe { region.base.add(offset) },
+        };
+        self.write(&code, |code| code[..bytes.len()].copy_from_slice(bytes));
+        code
+    }
+
+    pub fn free(&mut self, code: Code) {
+        let region = self.regions[code.region].as_mut().unwrap();
+        let k = region.free.iter().position(|&(offset, _)| offset > code.offset).unwrap_or(region.free.len());
+        region.free.insert(k, (code.offset, code.len));
+        // merge with the blocks after and before it
+        if k + 1 < region.free.len() && code.offset + code.len == region.free[k + 1].0 {
+            region.free[k].1 += region.free.remove(k + 1).1;
+        }
+        if k > 0 && region.free[k - 1].0 + region.free[k - 1].1 == code.offset {
+            region.free[k - 1].1 += region.free.remove(k).1;
+        }
+        if region.free == [(0, region.size)] {
+            self.regions[code.region] = None;
+        }
+        self.used -= code.len;
+        self.blocks -= 1;
+    }
+
+    // Runs f on the bytes of some code with its region writable.
+    pub fn write(&mut self, code: &Code, f: impl FnOnce(&mut [u8])) {
+        let region = self.regions[code.region].as_ref().unwrap();
+        region.protect(libc::PROT_READ | libc::PROT_WRITE);
+        f(unsafe { slice::from_raw_parts_mut(code.ptr, code.len) });
+        region.protect(libc::PROT_READ | libc::PROT_EXEC);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let regions = self.regions.iter().flatten();
+        let free_blocks = regions.clone().flat_map(|region| &region.free);
+        Stats {
+            regions: regions.clone().count(),
+            capacity: regions.map(|region| region.size).sum(),
+            used: self.used,
+            blocks: self.blocks,
+            free_blocks: free_blocks.clone().count(),
+            largest_free: free_blocks.map(|&(_, len)| len).max().unwrap_or(0),
+            evictions: self.evictions,
+        }
+    }
+}
+
+impl Region {
+    fn new(size: usize) -> Region {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = size.div_ceil(page) * page;
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            panic!("Couldn't map memory for code");
+        }
+        Region {
+            base: base as *mut u8,
+            size,
+            free: vec![(0, size)],
+        }
+    }
+
+    fn protect(&self, prot: libc::c_int) {
+        if unsafe { libc::mprotect(self.base as *mut libc::c_void, self.size, prot) } != 0 {
+            panic!("Couldn't change the protection of code");
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.size);
+        }
+    }
+}
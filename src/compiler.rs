@@ -1,13 +1,23 @@
 extern crate dynasm;
 extern crate dynasmrt;
 
+use super::elf::{self, Symbol};
+use super::jit::{self, Reloc, Runtime, TAIL_CALL};
+use super::opt::manager::PassManager;
 use super::program::*;
 use dynasm::dynasm;
 use dynasmrt::{DynasmApi, DynasmLabelApi};
 
 use std::collections::HashMap;
+use std::fs;
+use std::io;
 use std::mem;
+use std::process::Command;
+use std::sync::Arc;
 
+// The compiler programs used to run with before the JIT, which nothing calls
+// any more.
+#[allow(dead_code)]
 pub struct Compiler {
     asm: dynasmrt::x64::Assembler,
     pub index_map: HashMap<String, i64>,
@@ -15,11 +25,13 @@ pub struct Compiler {
     bril_map: HashMap<i64, Function>,
 }
 
+#[allow(dead_code)]
 pub struct AsmProgram {
     code: dynasmrt::ExecutableBuffer,
     start: dynasmrt::AssemblyOffset,
 }
 
+#[allow(dead_code)]
 impl Compiler {
     pub fn new(bril_program: Program) -> Compiler {
         let asm = dynasmrt::x64::Assembler::new().unwrap();
@@ -27,31 +39,29 @@ impl Compiler {
         let mut bril_map = HashMap::<i64, Function>::new();
         let asm_map = HashMap::<i64, AsmProgram>::new();
 
-        let mut i = 0;
-        for fun in &bril_program.functions {
+        for (i, fun) in (0..).zip(&bril_program.functions) {
             bril_map.insert(i, fun.clone());
             index_map.insert(fun.name.clone(), i);
-            i += 1;
         }
 
-        return Compiler {
-            asm: asm,
-            index_map: index_map,
-            asm_map: asm_map,
-            bril_map: bril_map,
-        };
+        Compiler {
+            asm,
+            index_map,
+            asm_map,
+            bril_map,
+        }
     }
 
     pub fn compile_and_run(&mut self, func_idx: i64) {
         if let Some(func_asm) = self.asm_map.get(&func_idx) {
             let func: fn(&Compiler) = unsafe { mem::transmute(func_asm.code.ptr(func_asm.start)) };
-            func(&self);
+            func(self);
         } else {
             let func_bril = self.bril_map.remove(&func_idx).unwrap();
             let func_asm = self.compile(&func_bril);
             let func: fn(&Compiler) = unsafe { mem::transmute(func_asm.code.ptr(func_asm.start)) };
             self.asm_map.insert(func_idx, func_asm);
-            func(&self)
+            func(self)
         }
     }
 
@@ -111,6 +121,12 @@ impl Compiler {
                                 "div" => {
                                     dynasm!(self.asm ; cqo ; idiv QWORD [rbp - b]);
                                 }
+                                "shl" => {
+                                    dynasm!(self.asm ; mov rcx, [rbp - b] ; shl rax, cl);
+                                }
+                                "shr" => {
+                                    dynasm!(self.asm ; mov rcx, [rbp - b] ; sar rax, cl);
+                                }
                                 "eq" => {
                                     dynasm!(self.asm ; cmp rax, [rbp - b] ; sete al ; movzx rax, al);
                                 }
@@ -159,11 +175,8 @@ impl Compiler {
                             (var_offsets.get(&args[0]), var_offsets.get(dest))
                         {
                             dynasm!(self.asm ; mov rax, [rbp - a]);
-                            match op.as_ref() {
-                                "not" => {
-                                    dynasm!(self.asm ; xor rax, 1);
-                                }
-                                _ => {}
+                            if op == "not" {
+                                dynasm!(self.asm ; xor rax, 1);
                             }
                             dynasm!(self.asm ; mov [rbp - d], rax);
                         }
@@ -183,7 +196,7 @@ impl Compiler {
                 Some(OpCode::Call) => {
                     if let Some(args) = &inst.args {
                         dynasm!(self.asm
-                            ; mov rax, QWORD Compiler::compile_and_run as _
+                            ; mov rax, QWORD Compiler::compile_and_run as *const () as _
                             ; mov rdi, [rbp - 8]
                             ; mov rsi, QWORD *self.index_map.get(&args[0]).unwrap()
                             ; call rax
@@ -198,10 +211,10 @@ impl Compiler {
                                 if let Some(&inst_type) = var_types.get(arg).as_ref() {
                                     match inst_type.as_ref() {
                                         "int" => {
-                                            dynasm!(self.asm ; mov rax, QWORD print_int as _);
+                                            dynasm!(self.asm ; mov rax, QWORD print_int as *const () as _);
                                         }
                                         "bool" => {
-                                            dynasm!(self.asm ; mov rax, QWORD print_bool as _);
+                                            dynasm!(self.asm ; mov rax, QWORD print_bool as *const () as _);
                                         }
                                         _ => {}
                                     }
@@ -210,11 +223,14 @@ impl Compiler {
                             }
                         }
                         dynasm!(self.asm
-                            ; mov rax, QWORD print_newline as _
+                            ; mov rax, QWORD print_newline as *const () as _
                             ; call rax
                         );
                     }
                 }
+                Some(OpCode::Phi) => {
+                    panic!("Phi nodes must be removed before compilation");
+                }
                 Some(OpCode::Nop) => {
                     dynasm!(self.asm ; nop);
                 }
@@ -275,35 +291,361 @@ impl Compiler {
         let mut asm_final = dynasmrt::x64::Assembler::new().unwrap();
         mem::swap(&mut self.asm, &mut asm_final);
         let code = asm_final.finalize().unwrap();
-        return AsmProgram {
-            code: code,
-            start: start,
-        };
+        AsmProgram {
+            code,
+            start,
+        }
+    }
+}
+
+// Compiles a whole program ahead of time with the JIT's optimizing tier and
+// writes it to output as a relocatable ELF object, or, with link, as an
+// executable linked by the system cc.
+pub fn compile(mut program: Program, pipeline: &Arc<PassManager>, output: &str, link: bool) -> io::Result<()> {
+    pipeline
+        .run_program_passes(&mut program)
+        .map_err(io::Error::other)?;
+    let object = compile_object(&Arc::new(program), pipeline)?;
+    if !link {
+        return fs::write(output, object);
     }
+    let path = format!("{}.o", output);
+    fs::write(&path, object)?;
+    let status = Command::new("cc").arg(&path).arg("-o").arg(output).status();
+    fs::remove_file(&path)?;
+    if status?.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other("cc failed"))
+    }
+}
+
+// Bril function f is the global bril_f, entered the way the JIT enters
+// compiled code: a buffer for tail calls, a pointer to the arguments and
+// their number, and a CallResult back. Calls between functions go straight
+// to each other, and the runtime after them prints, finishes tail calls and
+// has the `main` that parses bril_main's arguments.
+fn compile_object(program: &Arc<Program>, pipeline: &Arc<PassManager>) -> io::Result<Vec<u8>> {
+    let mut asm = dynasmrt::x64::Assembler::new().unwrap();
+    let mut funcs = Vec::new();
+    let mut symbols = Vec::new();
+    for func in &program.functions {
+        let ahead = jit::compile_ahead(program, func, pipeline.clone()).map_err(io::Error::other)?;
+        asm.align(16, 0xcc);
+        let base = asm.offset().0;
+        asm.extend(&ahead.code);
+        symbols.push(Symbol {
+            name: format!("bril_{}", func.name),
+            offset: base + ahead.start,
+            size: ahead.code.len() - ahead.start,
+            global: true,
+        });
+        funcs.push((base, ahead));
+    }
+    let mut calls = Vec::new();
+    runtime(&mut asm, &mut calls, &mut symbols, program)?;
+    let mut code = asm.finalize().unwrap().to_vec();
+
+    let index = |name: &str| program.functions.iter().position(|func| func.name == name).unwrap();
+    let offset = |name: &str| symbols.iter().find(|sym| sym.name == name).unwrap().offset;
+    for (func, (base, ahead)) in program.functions.iter().zip(&funcs) {
+        for &(site, reloc) in &ahead.relocs {
+            let site = base + site;
+            match reloc {
+                Reloc::Target(k) => {
+                    let (callee_base, callee) = &funcs[index(&ahead.callees[k])];
+                    lea(&mut code, site, callee_base + callee.native);
+                }
+                // every callee is compiled, so tail calls never take the
+                // path for one that isn't
+                Reloc::Stub(_) => {}
+                Reloc::Callee(k) => {
+                    let callee = index(&ahead.callees[k]) as i64;
+                    code[site + 2..site + 10].copy_from_slice(&callee.to_le_bytes());
+                }
+                Reloc::Runtime(Runtime::PrintInt) => lea(&mut code, site, offset("rt_print_int")),
+                Reloc::Runtime(Runtime::PrintNewline) => lea(&mut code, site, offset("rt_print_newline")),
+                Reloc::Runtime(Runtime::SetTailCall) => lea(&mut code, site, offset("rt_set_tail_call")),
+                Reloc::Runtime(Runtime::FinishTailCall) => lea(&mut code, site, offset("rt_finish_tail_call")),
+                // only tiers, guards and a limited code cache need these
+                _ => {
+                    let message = format!("{} needs the JIT's runtime", func.name);
+                    return Err(io::Error::other(message));
+                }
+            }
+        }
+    }
+    Ok(elf::object(&code, &symbols, &calls))
+}
+
+// Turns the `mov reg, QWORD 0` at site into a `lea reg, [rip + disp]` of
+// target, padded to the same length, so the code works wherever it's loaded.
+fn lea(code: &mut [u8], site: usize, target: usize) {
+    let reg = (code[site] & 1) << 3 | (code[site + 1] - 0xb8);
+    let disp = target as i64 - (site + 7) as i64;
+    code[site] = 0x48 | (reg >> 3) << 2;
+    code[site + 1] = 0x8d;
+    code[site + 2] = 0x05 | (reg & 7) << 3;
+    code[site + 3..site + 7].copy_from_slice(&(disp as i32).to_le_bytes());
+    code[site + 7..site + 10].copy_from_slice(&[0x0f, 0x1f, 0x00]);
+}
+
+// The runtime for code compiled ahead of time, on top of libc. The buffer
+// compiled code passes around holds a pending tail call: the callee's index,
+// the number of arguments and the arguments.
+fn runtime(
+    asm: &mut dynasmrt::x64::Assembler,
+    calls: &mut Vec<(usize, &'static str)>,
+    symbols: &mut Vec<Symbol>,
+    program: &Program,
+) -> io::Result<()> {
+    let int_fmt = asm.new_dynamic_label();
+    let count_fmt = asm.new_dynamic_label();
+    let arg_fmt = asm.new_dynamic_label();
+    let table = asm.new_dynamic_label();
+    let finish_tail_call = asm.new_dynamic_label();
+
+    asm.align(16, 0xcc);
+    let at = asm.offset().0;
+    dynasm!(asm
+        ; push rbp
+        ; mov rbp, rsp
+        ; mov rsi, rdi
+        ; lea rdi, [=>int_fmt]
+        ; xor eax, eax
+    );
+    call_libc(asm, calls, "printf");
+    dynasm!(asm
+        ; pop rbp
+        ; ret
+    );
+    define(asm, symbols, "rt_print_int", at, false);
+
+    let at = asm.offset().0;
+    dynasm!(asm
+        ; push rbp
+        ; mov rbp, rsp
+        ; mov edi, 10
+    );
+    call_libc(asm, calls, "putchar");
+    // line by line, as the interpreter prints, so nothing printed is lost if
+    // the program dies
+    dynasm!(asm ; xor edi, edi);
+    call_libc(asm, calls, "fflush");
+    dynasm!(asm
+        ; pop rbp
+        ; ret
+    );
+    define(asm, symbols, "rt_print_newline", at, false);
+
+    // (buffer, callee, args, num_args)
+    let at = asm.offset().0;
+    dynasm!(asm
+        ; mov [rdi], rsi
+        ; mov [rdi + 8], rcx
+        ; xor eax, eax
+        ; copy:
+        ; cmp rax, rcx
+        ; jge >copied
+        ; mov rsi, [rdx + rax * 8]
+        ; mov [rdi + rax * 8 + 16], rsi
+        ; inc rax
+        ; jmp <copy
+        ; copied:
+        ; ret
+    );
+    define(asm, symbols, "rt_set_tail_call", at, false);
+
+    // Makes the tail call in the buffer, and whichever that one leaves, off
+    // a copy of the arguments, since the callee may leave another.
+    let at = asm.offset().0;
+    dynasm!(asm
+        ; =>finish_tail_call
+        ; push rbp
+        ; mov rbp, rsp
+        ; push rbx
+        ; sub rsp, 8
+        ; mov rbx, rdi
+        ; again:
+        ; lea rsp, [rbp - 16]
+        ; mov rcx, [rbx + 8]
+        ; lea rax, [rcx * 8 + 15]
+        ; and rax, -16
+        ; sub rsp, rax
+        ; xor eax, eax
+        ; copy:
+        ; cmp rax, rcx
+        ; jge >copied
+        ; mov rdx, [rbx + rax * 8 + 16]
+        ; mov [rsp + rax * 8], rdx
+        ; inc rax
+        ; jmp <copy
+        ; copied:
+        ; mov rax, [rbx]
+        ; lea rdx, [=>table]
+        ; movsxd rax, DWORD [rdx + rax * 4]
+        ; add rax, rdx
+        ; mov rdi, rbx
+        ; mov rsi, rsp
+        ; mov rdx, rcx
+        ; call rax
+        ; cmp rax, TAIL_CALL as i32
+        ; je <again
+        ; mov rbx, [rbp - 8]
+        ; mov rsp, rbp
+        ; pop rbp
+        ; ret
+    );
+    define(asm, symbols, "rt_finish_tail_call", at, false);
+
+    // each function's entry, relative to the table
+    asm.align(4, 0xcc);
+    let at = asm.offset().0;
+    dynasm!(asm ; =>table);
+    for func in &program.functions {
+        let start = symbols.iter().find(|sym| sym.name == format!("bril_{}", func.name)).unwrap().offset;
+        asm.push_i32((start - at) as i32);
+    }
+
+    // int main(int argc, char **argv), with the tail call buffer and then
+    // bril_main's arguments on the stack, followed by strtoll's end pointer
+    let bril_main = match program.functions.iter().find(|func| func.name == "main") {
+        Some(func) => func,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "no main function")),
+    };
+    let num_args = bril_main.args.as_ref().map_or(0, Vec::len) as i32;
+    let max_args = program.functions.iter().map(|func| func.args.as_ref().map_or(0, Vec::len)).max().unwrap() as i32;
+    let args = 16 + 8 * max_args;
+    let end = args + 8 * num_args;
+    let usage = asm.new_dynamic_label();
+    let bad_arg = asm.new_dynamic_label();
+    let leave = asm.new_dynamic_label();
+    asm.align(16, 0xcc);
+    let at = asm.offset().0;
+    dynasm!(asm
+        ; push rbp
+        ; mov rbp, rsp
+        ; push rbx
+        ; push r12
+        ; sub rsp, 16 * ((end + 8 + 15) / 16)
+        ; mov r12, rsi
+        ; sub edi, 1
+        ; cmp edi, num_args
+        ; jne =>usage
+    );
+    for i in 0..num_args {
+        dynasm!(asm
+            ; mov rbx, [r12 + 8 * (i + 1)]
+            ; mov rdi, rbx
+            ; lea rsi, [rsp + end]
+            ; mov edx, 10
+        );
+        call_libc(asm, calls, "strtoll");
+        dynasm!(asm
+            ; mov rcx, [rsp + end]
+            ; cmp rcx, rbx
+            ; je =>bad_arg
+            ; cmp BYTE [rcx], 0
+            ; jne =>bad_arg
+            ; mov [rsp + args + 8 * i], rax
+        );
+    }
+    dynasm!(asm
+        ; mov rdi, rsp
+        ; lea rsi, [rsp + args]
+        ; mov edx, num_args
+    );
+    let start = symbols.iter().find(|sym| sym.name == "bril_main").unwrap().offset;
+    let next = asm.offset().0 + 5;
+    asm.push(0xe8);
+    asm.push_i32((start as i64 - next as i64) as i32);
+    dynasm!(asm
+        ; cmp rax, TAIL_CALL as i32
+        ; jne >done
+        ; mov rdi, rsp
+        ; call =>finish_tail_call
+        ; done:
+        ; xor eax, eax
+        ; jmp =>leave
+        ; =>usage
+        ; mov edi, 2
+        ; lea rsi, [=>count_fmt]
+        ; mov edx, num_args
+        ; xor eax, eax
+    );
+    call_libc(asm, calls, "dprintf");
+    dynasm!(asm
+        ; mov eax, 1
+        ; jmp =>leave
+        ; =>bad_arg
+        ; mov edi, 2
+        ; lea rsi, [=>arg_fmt]
+        ; mov rdx, rbx
+        ; xor eax, eax
+    );
+    call_libc(asm, calls, "dprintf");
+    dynasm!(asm
+        ; mov eax, 1
+        ; =>leave
+        ; lea rsp, [rbp - 16]
+        ; pop r12
+        ; pop rbx
+        ; pop rbp
+        ; ret
+    );
+    define(asm, symbols, "main", at, true);
+
+    dynasm!(asm ; =>int_fmt);
+    asm.extend(b"%ld \0");
+    dynasm!(asm ; =>count_fmt);
+    asm.extend(b"Expected %d arguments\n\0");
+    dynasm!(asm ; =>arg_fmt);
+    asm.extend(b"Couldn't parse argument %s\n\0");
+    Ok(())
+}
+
+fn define(asm: &dynasmrt::x64::Assembler, symbols: &mut Vec<Symbol>, name: &str, at: usize, global: bool) {
+    symbols.push(Symbol {
+        name: name.to_string(),
+        offset: at,
+        size: asm.offset().0 - at,
+        global,
+    });
+}
+
+// A call the linker points at a function of libc.
+fn call_libc(asm: &mut dynasmrt::x64::Assembler, calls: &mut Vec<(usize, &'static str)>, name: &'static str) {
+    asm.push(0xe8);
+    calls.push((asm.offset().0, name));
+    asm.push_i32(0);
 }
 
+#[allow(dead_code)]
 fn print_int(i: i64) {
     print!("{} ", i);
 }
 
+#[allow(dead_code)]
 fn print_bool(b: bool) {
     print!("{} ", b);
 }
 
+#[allow(dead_code)]
 fn print_newline() {
     println!()
 }
 
+#[allow(dead_code)]
 fn get_dyn_label(
     asm: &mut dynasmrt::x64::Assembler,
     labels: &mut HashMap<String, dynasmrt::DynamicLabel>,
     label: &str,
 ) -> dynasmrt::DynamicLabel {
     if let Some(&dyn_label) = labels.get(label) {
-        return dyn_label;
+        dyn_label
     } else {
         let dyn_label = asm.new_dynamic_label();
         labels.insert(label.to_string(), dyn_label);
-        return dyn_label;
+        dyn_label
     }
 }
@@ -0,0 +1,60 @@
+use super::program::*;
+use fnv::FnvHasher;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::PathBuf;
+
+// Bumped whenever what the JIT writes changes shape or meaning.
+const FORMAT: u32 = 1;
+
+// Code is only reused by the build that compiled it: the version number
+// stays put while the compiler changes under it, so build.rs names the
+// build by a hash of its source as well.
+const BUILD: &str = concat!(env!("CARGO_PKG_VERSION"), "-", env!("BUILD_ID"));
+
+// Compiled code kept between runs, one JSON file per entry, named by key.
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: &str) -> io::Result<DiskCache> {
+        fs::create_dir_all(dir)?;
+        Ok(DiskCache { dir: PathBuf::from(dir) })
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", key))
+    }
+
+    // Entries that can't be read are treated as missing and get rewritten.
+    pub fn load<T: DeserializeOwned>(&self, key: u64) -> Option<T> {
+        let text = fs::read_to_string(self.path(key)).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    // Written under another name first, so a run reading the entry never
+    // sees half of it.
+    pub fn store<T: Serialize>(&self, key: u64, entry: &T) -> io::Result<()> {
+        let tmp = self.dir.join(format!("{:016x}.{}.tmp", key, std::process::id()));
+        fs::write(&tmp, serde_json::to_string(entry)?)?;
+        fs::rename(&tmp, self.path(key))
+    }
+
+    // A hash of everything that went into some code: the compiler, how it
+    // was asked to compile, and the functions whose Bril it could have seen.
+    pub fn key(&self, options: &str, funcs: &[&Function]) -> u64 {
+        let mut hasher = FnvHasher::default();
+        hasher.write(BUILD.as_bytes());
+        hasher.write_u32(FORMAT);
+        hasher.write(options.as_bytes());
+        for func in funcs {
+            hasher.write(serde_json::to_string(func).unwrap().as_bytes());
+        }
+        hasher.finish()
+    }
+}
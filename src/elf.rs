@@ -0,0 +1,147 @@
+// Just enough of ELF64 for x86-64 to write a relocatable object with one
+// section of code, for the system linker to finish.
+
+// A symbol defined in the code, at `offset`.
+pub struct Symbol {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+    pub global: bool,
+}
+
+// A section header's name, type, flags, contents, link, info, alignment and
+// entry size.
+type Section<'a> = (u32, u32, u64, &'a [u8], u32, u32, u64, u64);
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const R_X86_64_PLT32: u64 = 4;
+
+// Sections, in the order they're written after the null section.
+const TEXT: u32 = 1;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const NUM_SECTIONS: u16 = 7;
+
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn add(&mut self, s: &str) -> u32 {
+        let at = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        at
+    }
+}
+
+// `calls` are the rel32 of calls to functions the object doesn't define,
+// by offset into the code and name. Those become undefined symbols.
+pub fn object(code: &[u8], symbols: &[Symbol], calls: &[(usize, &str)]) -> Vec<u8> {
+    let mut strtab = Strings(vec![0]);
+    let mut symtab = vec![0; 24];
+    // locals have to come before globals
+    let mut sorted: Vec<&Symbol> = symbols.iter().filter(|sym| !sym.global).collect();
+    let first_global = sorted.len() + 1;
+    sorted.extend(symbols.iter().filter(|sym| sym.global));
+    for sym in sorted {
+        let bind = if sym.global { STB_GLOBAL } else { 0 };
+        symbol(&mut symtab, strtab.add(&sym.name), bind << 4 | STT_FUNC, TEXT as u16, sym.offset, sym.size);
+    }
+    let mut undefined: Vec<&str> = Vec::new();
+    let mut rela = Vec::new();
+    for &(offset, name) in calls {
+        let k = match undefined.iter().position(|&n| n == name) {
+            Some(k) => k,
+            None => {
+                symbol(&mut symtab, strtab.add(name), STB_GLOBAL << 4, 0, 0, 0);
+                undefined.push(name);
+                undefined.len() - 1
+            }
+        };
+        let index = (symbols.len() + 1 + k) as u64;
+        put(&mut rela, offset as u64);
+        put(&mut rela, index << 32 | R_X86_64_PLT32);
+        put(&mut rela, -4i64 as u64);
+    }
+
+    let mut shstrtab = Strings(vec![0]);
+    let names = [
+        shstrtab.add(".text"),
+        shstrtab.add(".rela.text"),
+        shstrtab.add(".symtab"),
+        shstrtab.add(".strtab"),
+        shstrtab.add(".shstrtab"),
+        shstrtab.add(".note.GNU-stack"),
+    ];
+    let sections: [Section; 6] = [
+        (names[0], SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, code, 0, 0, 16, 0),
+        (names[1], SHT_RELA, SHF_INFO_LINK, &rela, SYMTAB, TEXT, 8, 24),
+        (names[2], SHT_SYMTAB, 0, &symtab, STRTAB, first_global as u32, 8, 24),
+        (names[3], SHT_STRTAB, 0, &strtab.0, 0, 0, 1, 0),
+        (names[4], SHT_STRTAB, 0, &shstrtab.0, 0, 0, 1, 0),
+        // no executable stack
+        (names[5], SHT_PROGBITS, 0, &[], 0, 0, 1, 0),
+    ];
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    out.extend_from_slice(&1u16.to_le_bytes()); // relocatable
+    out.extend_from_slice(&62u16.to_le_bytes()); // x86-64
+    out.extend_from_slice(&1u32.to_le_bytes());
+    put(&mut out, 0); // entry
+    put(&mut out, 0); // program headers
+    let shoff_at = out.len();
+    put(&mut out, 0);
+    out.extend_from_slice(&0u32.to_le_bytes());
+    for &half in &[64, 0, 0, 64, NUM_SECTIONS, NUM_SECTIONS - 2] {
+        out.extend_from_slice(&half.to_le_bytes());
+    }
+    let mut offsets = Vec::new();
+    for section in &sections {
+        while out.len() % 16 != 0 {
+            out.push(0);
+        }
+        offsets.push(out.len());
+        out.extend_from_slice(section.3);
+    }
+    while out.len() % 16 != 0 {
+        out.push(0);
+    }
+    let shoff = out.len() as u64;
+    out[shoff_at..shoff_at + 8].copy_from_slice(&shoff.to_le_bytes());
+    out.extend_from_slice(&[0; 64]);
+    for (section, offset) in sections.iter().zip(offsets) {
+        let &(name, kind, flags, contents, link, info, align, entsize) = section;
+        out.extend_from_slice(&name.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        put(&mut out, flags);
+        put(&mut out, 0);
+        put(&mut out, offset as u64);
+        put(&mut out, contents.len() as u64);
+        out.extend_from_slice(&link.to_le_bytes());
+        out.extend_from_slice(&info.to_le_bytes());
+        put(&mut out, align);
+        put(&mut out, entsize);
+    }
+    out
+}
+
+fn symbol(symtab: &mut Vec<u8>, name: u32, info: u8, section: u16, value: usize, size: usize) {
+    symtab.extend_from_slice(&name.to_le_bytes());
+    symtab.push(info);
+    symtab.push(0);
+    symtab.extend_from_slice(&section.to_le_bytes());
+    put(symtab, value as u64);
+    put(symtab, size as u64);
+}
+
+fn put(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
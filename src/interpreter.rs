@@ -1,7 +1,7 @@
 use super::program::*;
 use fnv::FnvHashMap;
 
-static RETURN_VAR: &'static str = "_ rho";
+static RETURN_VAR: &str = "_ rho";
 
 type Op = OpCode;
 
@@ -44,7 +44,7 @@ impl<'a> Interpreter<'a> {
         let env = &mut Env::new();
         for func in &self.program.functions {
             if func.name == "main" {
-                self.eval_func(&func, env);
+                self.eval_func(func, env);
             }
         }
     }
@@ -66,15 +66,33 @@ impl<'a> Interpreter<'a> {
     }
 
     pub fn eval_func(&self, func: &'a Function, env: &mut Env<'a>) -> bool {
+        let mut func = func;
         let mut i = 0;
         while i < func.instrs.len() {
             let instr = &func.instrs[i];
+            if let Some(name) = func.tail_call(i) {
+                // the callee takes over this frame instead of nesting in it
+                match self.program.functions.iter().find(|f| f.name == name) {
+                    Some(callee) => {
+                        let mut new_env = Env::new();
+                        self.bind_args(callee, &instr.args.as_ref().unwrap()[1..], env, &mut new_env);
+                        *env = new_env;
+                        func = callee;
+                        i = 0;
+                        continue;
+                    }
+                    None => {
+                        println!("Function not found");
+                        return false;
+                    }
+                }
+            }
             let action = self.eval_instr(instr, env);
             match action {
                 Ok(Action::Next) => {
                     i += 1;
                 }
-                Ok(Action::Jump(label)) => match Interpreter::find_label(&func, label) {
+                Ok(Action::Jump(label)) => match Interpreter::find_label(func, label) {
                     Some(v) => i = v,
                     None => {
                         println!("Couldn't find label to jump to");
@@ -91,7 +109,15 @@ impl<'a> Interpreter<'a> {
         true
     }
 
-    pub fn eval_instr(&self, instr: &'a Instruction, env: &mut Env<'a>) -> Result<Action, &str> {
+    fn bind_args(&self, func: &'a Function, args: &'a [String], env: &mut Env<'a>, new_env: &mut Env<'a>) {
+        if let Some(params) = &func.args {
+            for (param, arg) in params.iter().zip(args) {
+                new_env.put(&param.name, env.get(arg).unwrap());
+            }
+        }
+    }
+
+    pub fn eval_instr(&self, instr: &'a Instruction, env: &mut Env<'a>) -> Result<Action<'_>, &str> {
         match instr.op.as_ref().unwrap_or(&Op::Nop) {
             Op::Const => {
                 env.put(
@@ -122,6 +148,8 @@ impl<'a> Interpreter<'a> {
                     "mul" => env.put(dest, val1 * val2),
                     "sub" => env.put(dest, val1 - val2),
                     "div" => env.put(dest, val1 / val2),
+                    "shl" => env.put(dest, val1.wrapping_shl(val2 as u32)),
+                    "shr" => env.put(dest, val1.wrapping_shr(val2 as u32)),
                     "le" => env.put(dest, (val1 <= val2) as i64),
                     "lt" => env.put(dest, (val1 < val2) as i64),
                     "gt" => env.put(dest, (val1 > val2) as i64),
@@ -164,7 +192,7 @@ impl<'a> Interpreter<'a> {
             Op::Print => {
                 let instr_args = &(instr.args).as_ref().unwrap();
                 for arg in *instr_args {
-                    print!("{} ", env.get(&arg).unwrap());
+                    print!("{} ", env.get(arg).unwrap());
                 }
                 println!();
                 Ok(Action::Next)
@@ -172,7 +200,7 @@ impl<'a> Interpreter<'a> {
 
             Op::Jmp => {
                 let label = &instr.args.as_ref().unwrap()[0];
-                Ok(Action::Jump(&label))
+                Ok(Action::Jump(label))
             }
 
             Op::Br => {
@@ -193,18 +221,8 @@ impl<'a> Interpreter<'a> {
                 let mut called = false;
                 for func in &self.program.functions {
                     if func.name == *name {
-                        match &func.args {
-                            Some(params) => {
-                                for i in 0..params.len() {
-                                    let name = &params.get(i).unwrap().name;
-                                    let val = &instr_args[i + 1];
-                                    new_env.put(&name, env.get(&val).unwrap());
-                                }
-                            }
-
-                            None => (),
-                        }
-                        let result = self.eval_func(&func, new_env);
+                        self.bind_args(func, &instr_args[1..], env, new_env);
+                        let result = self.eval_func(func, new_env);
                         if !result {
                             return Err("Failed when calling function");
                         }
@@ -212,28 +230,26 @@ impl<'a> Interpreter<'a> {
                     }
                 }
                 if called {
-                    match instr.dest.as_ref() {
-                        Some(dest) => {
-                            env.put(dest, new_env.get("_ rho").unwrap());
-                        }
-
-                        None => (),
+                    if let Some(dest) = instr.dest.as_ref() {
+                        env.put(dest, new_env.get("_ rho").unwrap());
                     }
                     Ok(Action::Next)
                 } else {
-                    return Err("Function not found");
+                    Err("Function not found")
                 }
             }
 
             Op::Ret => {
                 let instr_args = &(instr.args).as_ref().unwrap();
-                if instr_args.len() > 0 {
+                if !instr_args.is_empty() {
                     let return_val = env.get(&instr_args[0]);
-                    env.put(&RETURN_VAR, return_val.unwrap());
+                    env.put(RETURN_VAR, return_val.unwrap());
                 }
                 Ok(Action::Return)
             }
 
+            Op::Phi => Err("Phi nodes must be removed before execution"),
+
             Op::Nop => Ok(Action::Next),
         }
     }
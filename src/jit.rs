@@ -1,5 +1,15 @@
+use super::code_cache::{Code, CodeCache, Stats};
+use super::disk_cache::DiskCache;
+use super::opt::cfg::Cfg;
+use super::opt::layout::{self, ENTRY};
+use super::opt::manager::{Context, PassManager};
+use super::opt::purity;
+use super::opt::regalloc::{self, Allocation, Location};
+use super::opt::remarks::{self, Remark};
+use super::opt::specialize::{self, Signature};
 use super::program::*;
 use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
 
 extern crate dynasm;
 extern crate dynasmrt;
@@ -7,16 +17,344 @@ extern crate dynasmrt;
 use dynasm::dynasm;
 use dynasmrt::{DynasmApi, DynasmLabelApi};
 
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::mem;
+use std::process;
+use std::ptr;
+use std::slice;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
 
-static RETURN_VAR: &'static str = "_ rho";
+static RETURN_VAR: &str = "_ rho";
 
 type Op = OpCode;
 
+// What jitted code returns, in rax and rdx.
+#[repr(C)]
+pub struct CallResult {
+    has_value: i64,
+    value: i64,
+}
+
+// In place of has_value, from a call that left a tail call in `tail_call`
+// for whoever called it to make.
+pub static TAIL_CALL: i64 = 2;
+
+// Calls the interpreter must have profiled before compiled code trusts that
+// a block it never reached stays that way. Recompiling after a failed guard
+// can cost more than the guards ever save.
+static SPECULATE_AFTER: i64 = 50;
+
+// Installs of other code that new code is safe from eviction for, so that
+// under a tight limit what was just compiled gets called before it's judged
+// by its calls.
+static GRACE: usize = 2;
+
+impl From<CallResult> for Option<i64> {
+    fn from(result: CallResult) -> Option<i64> {
+        if result.has_value == 1 {
+            Some(result.value)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<Option<i64>> for CallResult {
+    fn from(result: Option<i64>) -> CallResult {
+        CallResult {
+            has_value: result.is_some() as i64,
+            value: result.unwrap_or(0),
+        }
+    }
+}
+
+// How the interpreter enters compiled code, through the trampoline at `start`.
+type EntryFn = extern "C" fn(*mut Interpreter, *const i64, usize) -> CallResult;
+
+// How it enters at a loop header instead, with the frame's live values.
+type OsrFn = extern "C" fn(*mut Interpreter, *const i64) -> CallResult;
+
+// Baseline code is quick to get: no passes, every variable on the stack, and
+// a counter in the code that has the optimizing tier recompile the function
+// once it's been called often enough.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Tier {
+    Baseline,
+    Optimized,
+}
+
+// Call sites in the code are patched once their callees are compiled.
+// `calls` holds the callee and offset of the `mov rax, QWORD target` before
+// each call. Baseline code, and any code while the code cache has a limit,
+// counts its calls in `counter`. With a limit, code also keeps `frames` up
+// to date with how many frames could return into it, and `installed` is
+// how many installs came before it.
 pub struct AsmProgram {
-    code: dynasmrt::ExecutableBuffer,
+    code: Code,
+    start: dynasmrt::AssemblyOffset,
+    native: dynasmrt::AssemblyOffset,
+    calls: Vec<(i64, dynasmrt::AssemblyOffset)>,
+    // boxed, since the code has their addresses
+    #[allow(clippy::vec_box)]
+    guards: Vec<Box<Guard>>,
+    tier: Tier,
+    counter: Box<Cell<i64>>,
+    frames: Option<Box<Cell<i64>>>,
+    installed: usize,
+}
+
+// Where compiled code gives up on an assumption and hands the call back to
+// the interpreter: the instruction of `func` to resume at, the block control
+// got there from when only one block leads there, and where each value live
+// there is kept. Code that hits a guard passes its address and the values,
+// in the order of `vars`, to deoptimize.
+struct Guard {
+    func_idx: i64,
+    func: Arc<Function>,
+    index: usize,
+    from: Option<String>,
+    vars: Vec<(String, Loc)>,
+}
+
+impl AsmProgram {
+    fn ptr(&self, offset: dynasmrt::AssemblyOffset) -> *const u8 {
+        self.code.ptr(offset.0)
+    }
+}
+
+// Code for a function compiled to be entered at a loop header, which any
+// interpreted frame of the function that gets there can jump into. The
+// entry takes the values of `vars`, in order, from a buffer the interpreter
+// fills from its Env.
+pub struct OsrEntry {
+    func_asm: AsmProgram,
+    entry: dynasmrt::AssemblyOffset,
+    vars: Vec<String>,
+}
+
+// How often each label of a function was reached, and from which blocks.
+type Profile = HashMap<String, (i64, HashMap<String, i64>)>;
+
+// Everything compiling a function takes from the interpreter, copied so the
+// compile can run on the compiler thread while the interpreter carries on.
+// `hot` names the functions worth inlining, and `calls` is how often the
+// function was called. Code with a `key` is kept on disk under it.
+struct Job {
+    func_idx: i64,
+    func: Function,
+    label: Option<String>,
+    tier: Tier,
+    pipeline: Arc<PassManager>,
+    program: Arc<Program>,
+    hot: HashSet<String>,
+    calls: i64,
+    profile: Option<Profile>,
+    speculate: bool,
+    opt_n: Option<i64>,
+    count_calls: bool,
+    key: Option<u64>,
+}
+
+// Where jobs go to the compiler thread, and where what it made of them comes
+// back.
+type CompilerThread = (Sender<Job>, Receiver<Result<Compiled, String>>);
+
+// Code fresh from the compiler, or off the disk. Whatever depends on where
+// things are in this run is left to relocations the interpreter fills in.
+struct Compiled {
+    func_idx: i64,
+    label: Option<String>,
+    code: Vec<u8>,
     start: dynasmrt::AssemblyOffset,
+    native: dynasmrt::AssemblyOffset,
+    // boxed, since the code has their addresses
+    #[allow(clippy::vec_box)]
+    guards: Vec<Box<Guard>>,
+    tier: Tier,
+    counter: Box<Cell<i64>>,
+    frames: Option<Box<Cell<i64>>>,
+    osr: Option<(dynasmrt::AssemblyOffset, Vec<String>)>,
+    callees: Vec<Callee>,
+    relocs: Vec<(usize, Reloc)>,
+    remarks: Vec<Remark>,
+    calls: i64,
+    profile: Option<Profile>,
+    key: Option<u64>,
+}
+
+// A function compiled code calls, by name and constant arguments, since its
+// index depends on the clones the interpreter has made.
+#[derive(Clone, Deserialize, Serialize)]
+struct Callee {
+    name: String,
+    signature: Signature,
+}
+
+// What goes in the immediate of a `mov reg, QWORD 0` in compiled code once
+// it's installed, or laid out in an object: the address, stub or index of
+// callee k, a function of the runtime, the call counter, the frame count,
+// guard k, or the function's own index.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub enum Reloc {
+    Target(usize),
+    Stub(usize),
+    Callee(usize),
+    Runtime(Runtime),
+    Counter,
+    Frames,
+    Guard(usize),
+    Index,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub enum Runtime {
+    SetTailCall,
+    FinishTailCall,
+    TierUp,
+    Deoptimize,
+    PrintInt,
+    PrintNewline,
+}
+
+impl Runtime {
+    fn address(self) -> i64 {
+        match self {
+            Runtime::SetTailCall => set_tail_call as *const () as i64,
+            Runtime::FinishTailCall => finish_tail_call as *const () as i64,
+            Runtime::TierUp => tier_up as *const () as i64,
+            Runtime::Deoptimize => deoptimize as *const () as i64,
+            Runtime::PrintInt => print_int as *const () as i64,
+            Runtime::PrintNewline => print_newline as *const () as i64,
+        }
+    }
+}
+
+// A guard as it's kept on disk: where it resumes, the block it's reached from
+// and where it keeps each value.
+type SavedGuard = (usize, Option<String>, Vec<(String, Loc)>);
+
+// Compiled code as it's kept on disk. `resume` is the function its guards
+// resume in, and each guard is an index there and the values it saves.
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    code: Vec<u8>,
+    start: usize,
+    native: usize,
+    callees: Vec<Callee>,
+    relocs: Vec<(usize, Reloc)>,
+    resume: Option<Function>,
+    guards: Vec<SavedGuard>,
+    calls: i64,
+    profile: Option<Profile>,
+}
+
+// A function compiled ahead of time, with its relocations left for whoever
+// lays out the code. `callees` names callee k.
+pub struct Ahead {
+    pub code: Vec<u8>,
+    pub start: usize,
+    pub native: usize,
+    pub relocs: Vec<(usize, Reloc)>,
+    pub callees: Vec<String>,
+}
+
+// Compiles func with the optimizing tier but nothing from a profile, since
+// code compiled ahead of time has no interpreter to profile it or to fall
+// back to. Every function counts as hot, as in -opt.
+pub fn compile_ahead(program: &Arc<Program>, func: &Function, pipeline: Arc<PassManager>) -> Result<Ahead, String> {
+    let job = Job {
+        func_idx: 0,
+        func: func.clone(),
+        label: None,
+        tier: Tier::Optimized,
+        pipeline,
+        program: program.clone(),
+        hot: program.functions.iter().map(|f| f.name.clone()).collect(),
+        calls: 0,
+        profile: None,
+        speculate: false,
+        opt_n: None,
+        count_calls: false,
+        key: None,
+    };
+    let compiled = job.compile()?;
+    Ok(Ahead {
+        code: compiled.code,
+        start: compiled.start.0,
+        native: compiled.native.0,
+        relocs: compiled.relocs,
+        callees: compiled.callees.into_iter().map(|callee| callee.name).collect(),
+    })
+}
+
+// Results of calls to pure functions, keyed by callee and arguments. The
+// oldest entry is evicted once the cache is full.
+pub struct Memo {
+    capacity: usize,
+    cache: HashMap<(i64, Vec<i64>), Option<i64>>,
+    order: VecDeque<(i64, Vec<i64>)>,
+    hits: u64,
+    lookups: u64,
+}
+
+impl Memo {
+    pub fn new(capacity: usize) -> Memo {
+        Memo {
+            capacity,
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            lookups: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &(i64, Vec<i64>)) -> Option<Option<i64>> {
+        self.lookups += 1;
+        let result = self.cache.get(key).cloned();
+        if result.is_some() {
+            self.hits += 1;
+        }
+        result
+    }
+
+    pub fn insert(&mut self, key: (i64, Vec<i64>), result: Option<i64>) {
+        if self.capacity == 0 || self.cache.contains_key(&key) {
+            return;
+        }
+        if self.cache.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.cache.remove(&oldest);
+            }
+        }
+        self.order.push_back(key.clone());
+        self.cache.insert(key, result);
+    }
+
+    pub fn report(&self) {
+        let rate = if self.lookups == 0 {
+            0.0
+        } else {
+            100.0 * self.hits as f64 / self.lookups as f64
+        };
+        eprintln!(
+            "memo: {} hits / {} lookups ({:.1}%), {} entries",
+            self.hits,
+            self.lookups,
+            rate,
+            self.cache.len()
+        );
+    }
+}
+
+// How often a label was reached, and from which blocks.
+#[derive(Default)]
+pub struct LabelProfile<'a> {
+    count: i64,
+    preds: HashMap<&'a str, i64>,
 }
 
 pub struct Env<'a> {
@@ -30,8 +368,8 @@ impl<'a> Env<'a> {
         }
     }
 
-    pub fn get(&mut self, var_name: &'a str) -> Option<i64> {
-        self.env.get(&var_name).cloned()
+    pub fn get(&mut self, var_name: &str) -> Option<i64> {
+        self.env.get(var_name).cloned()
     }
 
     pub fn put(&mut self, var_name: &'a str, val: i64) {
@@ -46,83 +384,685 @@ pub enum Action<'a> {
 }
 
 pub struct Interpreter<'a> {
-    asm: dynasmrt::x64::Assembler,
     asm_map: HashMap<i64, AsmProgram>,
+    // code thrown away after a deopt, which frames may still be running
+    retired: Vec<AsmProgram>,
+    deopted: HashSet<i64>,
+    osr_entries: HashMap<(i64, &'a str), OsrEntry>,
+    loop_headers: HashMap<i64, HashSet<String>>,
+    stubs: HashMap<i64, Code>,
+    cache: CodeCache,
+    disk: Option<DiskCache>,
+    // functions whose code has been looked for on disk
+    looked_up: HashSet<i64>,
+    // Calls from the interpreter into compiled code that haven't returned.
+    // Retired code that doesn't count its frames is only freed once there
+    // are none, since until then some frame may still be running it.
+    native_depth: usize,
+    installs: usize,
     bril_map: HashMap<i64, &'a Function>,
     index_map: HashMap<&'a str, i64>,
-    label_map: HashMap<&'a str, HashMap<&'a str, i64>>,
+    spec_map: HashMap<(i64, Signature), i64>,
+    signatures: HashMap<i64, Signature>,
+    label_map: HashMap<&'a str, HashMap<&'a str, LabelProfile<'a>>>,
     profile_map: HashMap<i64, i64>,
-    program: &'a Program,
+    program: &'a Arc<Program>,
+    tail_call: Option<(i64, Vec<i64>)>,
+    memo: Option<Memo>,
+    pure_funcs: HashSet<i64>,
     jit: bool,
     jit_n: i64,
     osr: bool,
     osr_n: i64,
+    // calls of baseline code before the optimizing tier takes over; without
+    // it the JIT compiles straight to the optimizing tier
+    opt_n: Option<i64>,
+    pipeline: Arc<PassManager>,
+    // jobs go to the compiler thread, if there is one, and come back as code
+    compiler: Option<CompilerThread>,
+    queued: HashSet<(i64, Option<&'a str>)>,
 }
 
 impl<'a> Interpreter<'a> {
-    pub fn new(bril_ir: &'a Program, jit: bool, jit_n: i64, osr: bool, osr_n: i64) -> Interpreter<'a> {
-        let asm = dynasmrt::x64::Assembler::new().unwrap();
+    pub fn new(
+        bril_ir: &'a Arc<Program>,
+        jit: bool,
+        jit_n: i64,
+        osr: bool,
+        osr_n: i64,
+        pipeline: Arc<PassManager>,
+    ) -> Interpreter<'a> {
         let mut index_map = HashMap::<&'a str, i64>::new();
         let mut bril_map = HashMap::<i64, &'a Function>::new();
-        let mut label_map = HashMap::<&'a str, HashMap<&'a str, i64>>::new();
+        let mut label_map = HashMap::<&'a str, HashMap<&'a str, LabelProfile<'a>>>::new();
         let mut profile_map = HashMap::<i64, i64>::new();
         let asm_map = HashMap::<i64, AsmProgram>::new();
 
-        let mut i = 0;
-        for fun in &bril_ir.functions {
+        for (i, fun) in (0..).zip(&bril_ir.functions) {
             bril_map.insert(i, fun);
             index_map.insert(&fun.name, i);
             profile_map.insert(i, 0);
-            let mut label_profile_map = HashMap::<&'a str, i64>::new();
+            let mut label_profile_map = HashMap::<&'a str, LabelProfile<'a>>::new();
+            label_profile_map.insert(ENTRY, LabelProfile::default());
             for instr in &fun.instrs {
                 if let Some(label) = &instr.label {
-                    label_profile_map.insert(label, 0);
+                    label_profile_map.insert(label, LabelProfile::default());
                 }
             }
             label_map.insert(&fun.name, label_profile_map);
-            i += 1;
         }
 
         Interpreter {
-            asm: asm,
-            asm_map: asm_map,
-            bril_map: bril_map,
-            index_map: index_map,
-            label_map: label_map,
-            profile_map: profile_map,
+            asm_map,
+            retired: Vec::new(),
+            deopted: HashSet::new(),
+            osr_entries: HashMap::new(),
+            loop_headers: HashMap::new(),
+            stubs: HashMap::new(),
+            cache: CodeCache::new(),
+            disk: None,
+            looked_up: HashSet::new(),
+            native_depth: 0,
+            installs: 0,
+            bril_map,
+            index_map,
+            spec_map: HashMap::new(),
+            signatures: HashMap::new(),
+            label_map,
+            profile_map,
             program: bril_ir,
-            jit: jit,
-            jit_n: jit_n,
-            osr: osr,
-            osr_n: osr_n,
+            tail_call: None,
+            memo: None,
+            pure_funcs: HashSet::new(),
+            jit,
+            jit_n,
+            osr,
+            osr_n,
+            opt_n: None,
+            pipeline,
+            compiler: None,
+            queued: HashSet::new(),
+        }
+    }
+
+    // Opt-in, since it only pays off for programs that repeat pure calls.
+    pub fn enable_memo(&mut self, capacity: usize) {
+        let pure = purity::pure_functions(self.program);
+        self.pure_funcs = self
+            .index_map
+            .iter()
+            .filter(|(name, _)| pure.contains(**name))
+            .map(|(_, &idx)| idx)
+            .collect();
+        self.memo = Some(Memo::new(capacity));
+    }
+
+    // Has -jit compile to the baseline tier, and baseline code called opt_n
+    // times recompiled by the optimizing tier.
+    pub fn enable_tiers(&mut self, opt_n: i64) {
+        self.opt_n = Some(opt_n);
+    }
+
+    // Keeps compiled code to about `bytes`, evicting the functions called
+    // least to make room.
+    pub fn limit_code(&mut self, bytes: usize) {
+        self.cache.set_limit(bytes);
+    }
+
+    pub fn code_stats(&self) -> Stats {
+        self.cache.stats()
+    }
+
+    // Keeps optimized code in dir, to start functions in compiled code on
+    // later runs of the same program.
+    pub fn enable_disk_cache(&mut self, dir: &str) -> io::Result<()> {
+        self.disk = Some(DiskCache::new(dir)?);
+        Ok(())
+    }
+
+    // Compiles on a thread of its own, so hot functions keep being
+    // interpreted until their code is ready instead of waiting for it.
+    pub fn enable_background(&mut self) {
+        let (jobs, job_rx) = mpsc::channel::<Job>();
+        let (code_tx, code) = mpsc::channel();
+        let keep_remarks = remarks::is_enabled();
+        thread::spawn(move || {
+            if keep_remarks {
+                remarks::enable();
+            }
+            for job in job_rx {
+                let compiled = job.compile().map(|mut compiled| {
+                    compiled.remarks = remarks::take();
+                    compiled
+                });
+                if code_tx.send(compiled).is_err() {
+                    break;
+                }
+            }
+        });
+        self.compiler = Some((jobs, code));
+    }
+
+    // Hands a job to the compiler thread, or compiles it right away without one.
+    fn submit(&mut self, key: (i64, Option<&'a str>), job: Job) {
+        match &self.compiler {
+            Some((jobs, _)) => {
+                self.queued.insert(key);
+                jobs.send(job).unwrap();
+            }
+            None => {
+                let compiled = job.compile();
+                self.accept(compiled);
+            }
+        }
+    }
+
+    // Installs whatever the compiler thread has finished.
+    fn poll(&mut self) {
+        let mut done = Vec::new();
+        if let Some((_, code)) = &self.compiler {
+            loop {
+                match code.try_recv() {
+                    Ok(compiled) => done.push(compiled),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("The compiler thread died"),
+                }
+            }
+        }
+        for compiled in done {
+            self.accept(compiled);
         }
     }
 
-    pub fn handle_osr(&mut self, env: &mut Env<'a>, func_idx: i64, label: &'a str) -> Option<i64> {
-        let func_bril = self.bril_map.remove(&func_idx).unwrap();
-        let (func_asm, start) = self.compile(&func_bril, Some(env), Some(label));
-        let func: fn(&Interpreter, Vec<i64>) -> Option<i64> =
-            unsafe { mem::transmute(func_asm.code.ptr(start)) };
-        self.asm_map.insert(func_idx, func_asm);
-        return func(&self, Vec::new());
+    // Code that failed to compile means a pass broke the function, which
+    // -verify reports instead of running what it left.
+    fn accept(&mut self, compiled: Result<Compiled, String>) {
+        match compiled {
+            Ok(compiled) => self.finish(compiled),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Fills in the calls in new code and installs it, in place of whatever
+    // code the function had, which frames may still be running.
+    fn finish(&mut self, compiled: Compiled) {
+        remarks::extend(compiled.remarks);
+        if let (Some(disk), Some(key)) = (&self.disk, compiled.key) {
+            let entry = Entry {
+                code: compiled.code.clone(),
+                start: compiled.start.0,
+                native: compiled.native.0,
+                callees: compiled.callees.clone(),
+                relocs: compiled.relocs.clone(),
+                resume: compiled.guards.first().map(|guard| (*guard.func).clone()),
+                guards: compiled
+                    .guards
+                    .iter()
+                    .map(|guard| (guard.index, guard.from.clone(), guard.vars.clone()))
+                    .collect(),
+                calls: compiled.calls,
+                profile: compiled.profile,
+            };
+            if let Err(e) = disk.store(key, &entry) {
+                eprintln!("Couldn't write to the JIT cache: {}", e);
+            }
+        }
+        // before any calls are pointed at code that's about to be evicted
+        self.make_room(compiled.code.len());
+        let func_idx = compiled.func_idx;
+        let mut code = compiled.code;
+        let callees: Vec<_> = compiled
+            .callees
+            .into_iter()
+            .map(|callee| {
+                let idx = self.callee_index(&callee.name, callee.signature);
+                (idx, self.stub(idx), self.call_target(idx))
+            })
+            .collect();
+        let mut calls = Vec::new();
+        let mut values = Vec::new();
+        for &(offset, reloc) in &compiled.relocs {
+            let value = match reloc {
+                Reloc::Target(k) => {
+                    calls.push((callees[k].0, dynasmrt::AssemblyOffset(offset)));
+                    callees[k].2 as i64
+                }
+                Reloc::Stub(k) => callees[k].1 as i64,
+                Reloc::Callee(k) => callees[k].0,
+                Reloc::Runtime(runtime) => runtime.address(),
+                Reloc::Counter => compiled.counter.as_ptr() as i64,
+                Reloc::Frames => compiled.frames.as_ref().unwrap().as_ptr() as i64,
+                Reloc::Guard(k) => &*compiled.guards[k] as *const Guard as i64,
+                Reloc::Index => func_idx,
+            };
+            values.push((offset, value));
+        }
+        patch(&mut code, &values);
+        let func_asm = AsmProgram {
+            code: self.cache.insert(&code),
+            start: compiled.start,
+            native: compiled.native,
+            calls,
+            guards: compiled.guards,
+            tier: compiled.tier,
+            counter: compiled.counter,
+            frames: compiled.frames,
+            installed: self.installs,
+        };
+        self.installs += 1;
+        match compiled.label {
+            Some(label) => {
+                let func = self.bril_map[&func_idx];
+                let label = func
+                    .instrs
+                    .iter()
+                    .filter_map(|instr| instr.label.as_deref())
+                    .find(|&l| l == label)
+                    .unwrap();
+                let (entry, vars) = compiled.osr.unwrap();
+                let osr_entry = OsrEntry {
+                    func_asm,
+                    entry,
+                    vars,
+                };
+                self.osr_entries.insert((func_idx, label), osr_entry);
+                self.queued.remove(&(func_idx, Some(label)));
+            }
+            None => {
+                if let Some(old) = self.asm_map.insert(func_idx, func_asm) {
+                    self.retired.push(old);
+                }
+                self.link(func_idx);
+                self.queued.remove(&(func_idx, None));
+            }
+        }
+    }
+
+    // A copy of what compiling func, or an OSR entry into it at label, needs
+    // from the interpreter as it is now.
+    fn job(&self, func_idx: i64, func: Function, label: Option<&str>, tier: Tier, pipeline: Arc<PassManager>) -> Job {
+        let hot = self
+            .index_map
+            .iter()
+            .filter(|(_, idx)| self.profile_map[idx] >= self.jit_n)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        let profile = match tier {
+            Tier::Baseline => None,
+            Tier::Optimized => self.label_map.get::<str>(&func.name).map(|label_profile_map| {
+                label_profile_map
+                    .iter()
+                    .map(|(label, p)| {
+                        let preds = p.preds.iter().map(|(from, &n)| (from.to_string(), n)).collect();
+                        (label.to_string(), (p.count, preds))
+                    })
+                    .collect()
+            }),
+        };
+        let calls = self.profile_map[&func_idx];
+        let key = match (tier, label) {
+            (Tier::Optimized, None) => self.disk_key(&func),
+            _ => None,
+        };
+        Job {
+            func_idx,
+            func,
+            label: label.map(str::to_string),
+            tier,
+            pipeline,
+            program: self.program.clone(),
+            hot,
+            calls,
+            profile,
+            speculate: label.is_none() && calls > SPECULATE_AFTER && !self.deopted.contains(&func_idx),
+            opt_n: self.opt_n,
+            count_calls: self.cache.limit().is_some(),
+            key,
+        }
+    }
+
+    // What code for func is kept on disk under: the pipeline, whether it
+    // counts its calls, and func with every function it could inline.
+    fn disk_key(&self, func: &Function) -> Option<u64> {
+        let disk = self.disk.as_ref()?;
+        let mut funcs = vec![func];
+        let mut i = 0;
+        while i < funcs.len() {
+            for instr in &funcs[i].instrs {
+                if let (Some(OpCode::Call), Some(args)) = (&instr.op, &instr.args) {
+                    let callee = &self.program.functions[self.index_map[&*args[0]] as usize];
+                    if !funcs.iter().any(|f| f.name == callee.name) {
+                        funcs.push(callee);
+                    }
+                }
+            }
+            i += 1;
+        }
+        let options = format!("{} {}", self.pipeline.names(), self.cache.limit().is_some());
+        Some(disk.key(&options, &funcs))
+    }
+
+    // Installs the code kept on disk for a function, if there is any, along
+    // with the profile it was compiled from.
+    fn load(&mut self, func_idx: i64) {
+        let func_bril = self.func_bril(func_idx);
+        let key = match self.disk_key(&func_bril) {
+            Some(key) => key,
+            None => return,
+        };
+        let entry: Entry = match self.disk.as_ref().and_then(|disk| disk.load(key)) {
+            Some(entry) => entry,
+            None => return,
+        };
+        remarks::applied("jit", &func_bril.name, None, &|| "loaded from the cache".to_string());
+        *self.profile_map.get_mut(&func_idx).unwrap() += entry.calls;
+        if let (Some(label_profile_map), Some(profile)) = (self.label_map.get_mut::<str>(&func_bril.name), &entry.profile) {
+            let labels: Vec<&'a str> = label_profile_map.keys().cloned().collect();
+            for (label, (count, preds)) in profile {
+                if let Some(p) = label_profile_map.get_mut::<str>(label) {
+                    p.count += count;
+                    for (from, n) in preds {
+                        if let Some(&from) = labels.iter().find(|&&l| l == from) {
+                            *p.preds.entry(from).or_insert(0) += n;
+                        }
+                    }
+                }
+            }
+        }
+        let resume = entry.resume.map(Arc::new);
+        let guards = entry
+            .guards
+            .into_iter()
+            .map(|(index, from, vars)| {
+                Box::new(Guard {
+                    func_idx,
+                    func: resume.clone().unwrap(),
+                    index,
+                    from,
+                    vars,
+                })
+            })
+            .collect();
+        self.finish(Compiled {
+            func_idx,
+            label: None,
+            code: entry.code,
+            start: dynasmrt::AssemblyOffset(entry.start),
+            native: dynasmrt::AssemblyOffset(entry.native),
+            guards,
+            tier: Tier::Optimized,
+            counter: Box::new(Cell::new(0)),
+            frames: self.cache.limit().map(|_| Box::new(Cell::new(0))),
+            osr: None,
+            callees: entry.callees,
+            relocs: entry.relocs,
+            remarks: Vec::new(),
+            calls: entry.calls,
+            profile: entry.profile,
+            key: None,
+        });
+    }
+
+    // Runs compiled code, and frees whatever retired code it's done with.
+    fn call_native(&mut self, enter: impl FnOnce(&mut Interpreter<'a>) -> CallResult) -> Option<i64> {
+        self.native_depth += 1;
+        let result = enter(self);
+        self.native_depth -= 1;
+        self.collect();
+        result.into()
+    }
+
+    // Frees the retired code no frame can return into: code whose frames
+    // have all left it, or any code once no compiled frames are left at all.
+    fn collect(&mut self) {
+        let native_depth = self.native_depth;
+        let (done, running) = mem::take(&mut self.retired).into_iter().partition(|func_asm: &AsmProgram| {
+            native_depth == 0 || func_asm.frames.as_ref().is_some_and(|frames| frames.get() == 0)
+        });
+        self.retired = running;
+        for func_asm in done {
+            self.cache.free(func_asm.code);
+        }
+    }
+
+    // Evicts the code called least since it was compiled until `len` more
+    // bytes fit under the limit, which retired code still running counts
+    // against too. Code in its grace period is kept, and the limit gives if
+    // there's nothing else left to evict.
+    fn make_room(&mut self, len: usize) {
+        let limit = match self.cache.limit() {
+            Some(limit) => limit,
+            None => return,
+        };
+        self.collect();
+        while self.cache.used() + len > limit {
+            let installs = self.installs;
+            let is_old = |func_asm: &AsmProgram| installs - func_asm.installed >= GRACE;
+            let funcs = self
+                .asm_map
+                .iter()
+                .filter(|(_, func_asm)| is_old(func_asm))
+                .map(|(&idx, func_asm)| (func_asm.counter.get(), (idx, None)));
+            let entries = self
+                .osr_entries
+                .iter()
+                .filter(|(_, osr_entry)| is_old(&osr_entry.func_asm))
+                .map(|(&(idx, label), osr_entry)| (osr_entry.func_asm.counter.get(), (idx, Some(label))));
+            match funcs.chain(entries).min_by_key(|&(calls, _)| calls) {
+                Some((calls, key)) => self.evict(key, calls),
+                None => break,
+            }
+            self.collect();
+        }
+    }
+
+    // Throws away a function's code, or an OSR entry, leaving calls to the
+    // interpreter, which has to find the function hot again to recompile it.
+    fn evict(&mut self, key: (i64, Option<&'a str>), calls: i64) {
+        let (func_idx, label) = key;
+        self.cache.record_eviction();
+        let func_asm = match label {
+            Some(label) => self.osr_entries.remove(&(func_idx, label)).unwrap().func_asm,
+            None => {
+                remarks::missed("jit", &self.bril_map[&func_idx].name, None, &|| {
+                    format!("evicted after {} calls", calls)
+                });
+                self.profile_map.insert(func_idx, 0);
+                self.asm_map.remove(&func_idx).unwrap()
+            }
+        };
+        self.retired.push(func_asm);
+        if label.is_none() {
+            self.link(func_idx);
+        }
+    }
+
+    // Whether compiled code can be entered at a loop header, compiling an
+    // entry there the first time any frame of the function gets this far.
+    fn has_osr_entry(&mut self, func_idx: i64, label: &'a str) -> bool {
+        self.poll();
+        let key = (func_idx, Some(label));
+        if !self.osr_entries.contains_key(&(func_idx, label)) && !self.queued.contains(&key) {
+            let func_bril = self.bril_map[&func_idx];
+            remarks::applied("osr", &func_bril.name, Interpreter::find_label(func_bril, label), &|| {
+                format!("compiled an entry at {}", label)
+            });
+            // OSR entry needs the function exactly as the interpreter sees it
+            let pipeline = Arc::new(PassManager::default());
+            let job = self.job(func_idx, func_bril.clone(), Some(label), Tier::Optimized, pipeline);
+            self.submit(key, job);
+        }
+        self.osr_entries.contains_key(&(func_idx, label))
+    }
+
+    // Finishes the frame in compiled code from a loop header.
+    pub fn handle_osr(&mut self, env: &mut Env, func_idx: i64, label: &'a str) -> Option<i64> {
+        let osr_entry = &self.osr_entries[&(func_idx, label)];
+        osr_entry.func_asm.counter.set(osr_entry.func_asm.counter.get() + 1);
+        // values that are live but not yet defined can't be read anyway
+        let frame: Vec<i64> = osr_entry.vars.iter().map(|var| env.get(var).unwrap_or(0)).collect();
+        let func: OsrFn = unsafe { mem::transmute(osr_entry.func_asm.ptr(osr_entry.entry)) };
+        self.call_native(|interp| func(interp, frame.as_ptr()))
+    }
+
+    fn is_loop_header(&mut self, func_idx: i64, label: &str) -> bool {
+        let func = self.bril_map[&func_idx];
+        let headers = self.loop_headers.entry(func_idx).or_insert_with(|| {
+            let cfg = Cfg::new(func);
+            cfg.natural_loops()
+                .iter()
+                .filter_map(|lp| cfg.blocks[lp.header].label.clone())
+                .collect()
+        });
+        headers.contains(label)
     }
 
     pub fn handle_call(&mut self, func_idx: i64, args: Vec<i64>) -> Option<i64> {
+        let mut func_idx = func_idx;
+        let mut args = args;
+        // every call in a chain of tail calls returns the same result
+        let mut keys = Vec::new();
+        // Tail calls unwind back to here before the callee runs, so neither
+        // interpreted nor native frames pile up for them.
+        let result = loop {
+            if let Some(memo) = &mut self.memo {
+                if self.pure_funcs.contains(&func_idx) {
+                    let key = (func_idx, args.clone());
+                    if let Some(result) = memo.get(&key) {
+                        break result;
+                    }
+                    keys.push(key);
+                }
+            }
+            let result = self.dispatch_call(func_idx, args);
+            match self.tail_call.take() {
+                Some((next_idx, next_args)) => {
+                    func_idx = next_idx;
+                    args = next_args;
+                }
+                None => break result,
+            }
+        };
+        if let Some(memo) = &mut self.memo {
+            for key in keys {
+                memo.insert(key, result);
+            }
+        }
+        result
+    }
+
+    pub fn set_tail_call(&mut self, func_idx: i64, args: Vec<i64>) {
+        self.tail_call = Some((func_idx, args));
+    }
+
+    // Call sites start out calling a per-function stub, which moves arguments
+    // from where the internal calling convention puts them into an array for
+    // handle_call. That counts the call and compiles the callee once it's hot.
+    // Every call site shares it, so it takes as many arguments as the callee
+    // declares.
+    fn stub(&mut self, func_idx: i64) -> usize {
+        if !self.stubs.contains_key(&func_idx) {
+            let num_args = self.bril_map[&func_idx].args.as_ref().map_or(0, |args| args.len());
+            let mut asm = dynasmrt::x64::Assembler::new().unwrap();
+            dynasm!(asm
+                ; push rbp
+                ; mov rbp, rsp
+                ; sub rsp, 16 * ((num_args as i32 + 1) / 2)
+            );
+            for i in 0..num_args {
+                if let Some(&reg) = ARG_REGS.get(i) {
+                    dynasm!(asm ; mov [rsp + 8 * i as i32], Rq(reg));
+                } else {
+                    let off = 16 + 8 * (i - ARG_REGS.len()) as i32;
+                    dynasm!(asm
+                        ; mov rax, [rbp + off]
+                        ; mov [rsp + 8 * i as i32], rax
+                    );
+                }
+            }
+            dynasm!(asm
+                ; mov rsi, QWORD func_idx
+                ; mov rdx, rsp
+                ; mov rcx, QWORD num_args as i64
+                ; mov rax, QWORD handle_call as *const () as _
+                ; call rax
+                ; mov rsp, rbp
+                ; pop rbp
+                ; ret
+            );
+            let stub = self.cache.insert(&asm.finalize().unwrap());
+            self.stubs.insert(func_idx, stub);
+        }
+        self.stubs[&func_idx].ptr(0) as usize
+    }
+
+    // What a new call site should call: the callee itself if it's compiled,
+    // otherwise its stub. Calls to memoized functions always take the stub,
+    // since handle_call does the lookup.
+    fn call_target(&mut self, func_idx: i64) -> usize {
+        let stub = self.stub(func_idx);
+        if self.memo.is_some() && self.pure_funcs.contains(&func_idx) {
+            return stub;
+        }
+        match self.asm_map.get(&func_idx) {
+            Some(func_asm) => func_asm.ptr(func_asm.native) as usize,
+            None => stub,
+        }
+    }
+
+    // Patches calls to a function, in all compiled code including OSR entries
+    // and code still running after it was thrown away, to go where a new call
+    // site would:
+    // straight to the function once it's compiled, back to its stub once that
+    // code is thrown away.
+    fn link(&mut self, func_idx: i64) {
+        if self.memo.is_some() && self.pure_funcs.contains(&func_idx) {
+            return;
+        }
+        let target = match (self.asm_map.get(&func_idx), self.stubs.get(&func_idx)) {
+            (Some(func_asm), _) => func_asm.ptr(func_asm.native) as i64,
+            (None, Some(stub)) => stub.ptr(0) as i64,
+            (None, None) => return,
+        };
+        let osr_code = self.osr_entries.values_mut().map(|osr_entry| &mut osr_entry.func_asm);
+        for func_asm in self.asm_map.values_mut().chain(&mut self.retired).chain(osr_code) {
+            let sites: Vec<_> = func_asm
+                .calls
+                .iter()
+                .filter(|(callee, _)| *callee == func_idx)
+                .map(|&(_, site)| (site.0, target))
+                .collect();
+            if !sites.is_empty() {
+                self.cache.write(&func_asm.code, |code| patch(code, &sites));
+            }
+        }
+    }
+
+    fn dispatch_call(&mut self, func_idx: i64, args: Vec<i64>) -> Option<i64> {
+        self.poll();
+        if self.jit && self.disk.is_some() && self.looked_up.insert(func_idx) {
+            self.load(func_idx);
+        }
         if let Some(func_asm) = self.asm_map.get(&func_idx) {
-            let func: fn(&Interpreter, Vec<i64>) -> Option<i64> =
-                unsafe { mem::transmute(func_asm.code.ptr(func_asm.start)) };
-            return func(&self, args);
+            let func: EntryFn = unsafe { mem::transmute(func_asm.ptr(func_asm.start)) };
+            return self.call_native(|interp| func(interp, args.as_ptr(), args.len()));
         } else {
             if let Some(&func_profile_data) = &self.profile_map.get(&func_idx) {
                 self.profile_map.insert(func_idx, func_profile_data + 1);
                 if self.jit && func_profile_data >= self.jit_n {
-                    let func_bril = self.bril_map.remove(&func_idx).unwrap();
-                    let (func_asm, start) = self.compile(&func_bril, None, None);
-                    let func: fn(&Interpreter, Vec<i64>) -> Option<i64> =
-                        unsafe { mem::transmute(func_asm.code.ptr(start)) };
-                    self.asm_map.insert(func_idx, func_asm);
-                    let x = func(&self, args);
-                    return x;
+                    let tier = if self.opt_n.is_some() { Tier::Baseline } else { Tier::Optimized };
+                    self.install(func_idx, tier, &|| match tier {
+                        Tier::Baseline => format!("compiled by the baseline tier after {} calls", func_profile_data),
+                        Tier::Optimized => format!("compiled after {} calls", func_profile_data),
+                    });
+                }
+                // with a compiler thread, the code may not be ready yet
+                if let Some(func_asm) = self.asm_map.get(&func_idx) {
+                    let func: EntryFn = unsafe { mem::transmute(func_asm.ptr(func_asm.start)) };
+                    return self.call_native(|interp| func(interp, args.as_ptr(), args.len()));
                 } else {
                     let func_bril = self.bril_map.get(&func_idx).unwrap();
                     let name = &func_bril.name;
@@ -130,17 +1070,12 @@ impl<'a> Interpreter<'a> {
                     let mut called = false;
                     for func in &self.program.functions {
                         if func.name == *name {
-                            match &func.args {
-                                Some(params) => {
-                                    for i in 0..params.len() {
-                                        let name = &params.get(i).unwrap().name;
-                                        new_env.put(&name, args[i]);
-                                    }
+                            if let Some(params) = &func.args {
+                                for (param, &arg) in params.iter().zip(&args) {
+                                    new_env.put(&param.name, arg);
                                 }
-
-                                None => (),
                             }
-                            let result = self.eval_func(&func, new_env);
+                            let result = self.eval_func(func, new_env);
                             if !result {
                                 panic!("Failed when calling function");
                             }
@@ -159,183 +1094,461 @@ impl<'a> Interpreter<'a> {
         None
     }
 
-    pub fn compile(
-        &mut self,
-        bril_func: &'a Function,
-        env: Option<&mut Env<'a>>,
-        label: Option<&'a str>,
-    ) -> (AsmProgram, dynasmrt::AssemblyOffset) {
-        let mut var_offsets = HashMap::<&'a str, i32>::new();
-        let mut var_types = HashMap::<&'a str, String>::new();
-        let mut labels = HashMap::<&'a str, dynasmrt::DynamicLabel>::new();
-        let mut num_vars = 2;
-
-        if let Some(args) = &bril_func.args {
-            for arg in args {
-                var_offsets.insert(&arg.name, 8 * num_vars);
-                num_vars += 1;
-            }
-        }
-        for inst in &bril_func.instrs {
-            if let Some(dest) = &inst.dest {
-                if !var_offsets.contains_key::<str>(&dest) {
-                    var_offsets.insert(&dest, 8 * num_vars);
-                    num_vars += 1;
-                }
-                if !var_types.contains_key::<str>(&dest) {
-                    if let Some(inst_type) = &inst.r#type {
-                        var_types.insert(&dest, inst_type.to_string());
+    // Compiles a function, or its specialized clone, to replace whatever code
+    // it has, unless that's already under way.
+    fn install(&mut self, func_idx: i64, tier: Tier, reason: &dyn Fn() -> String) {
+        if self.queued.contains(&(func_idx, None)) {
+            return;
+        }
+        let func_bril = self.func_bril(func_idx);
+        remarks::applied("jit", &func_bril.name, None, reason);
+        let pipeline = match tier {
+            Tier::Baseline => Arc::new(PassManager::default()),
+            Tier::Optimized => self.pipeline.clone(),
+        };
+        let job = self.job(func_idx, func_bril, None, tier, pipeline);
+        self.submit((func_idx, None), job);
+    }
+
+    // The function to compile for an index: the function itself, or a clone
+    // specialized to the constant arguments it's called with.
+    fn func_bril(&self, func_idx: i64) -> Function {
+        let func_bril = self.bril_map[&func_idx];
+        match self.signatures.get(&func_idx) {
+            Some(signature) => {
+                let name = format!("{}.spec", func_bril.name);
+                specialize::clone(func_bril, signature, name)
+            }
+            None => func_bril.clone(),
+        }
+    }
+
+    // Called by baseline code whose counter ran out. Frames already running
+    // it finish there; calls from now on get the optimized code.
+    fn tier_up(&mut self, func_idx: i64) {
+        self.poll();
+        let is_baseline = match self.asm_map.get(&func_idx) {
+            Some(func_asm) => func_asm.tier == Tier::Baseline,
+            None => false,
+        };
+        if is_baseline {
+            let opt_n = self.opt_n.unwrap();
+            self.install(func_idx, Tier::Optimized, &|| {
+                format!("recompiled by the optimizing tier after {} calls", opt_n)
+            });
+        }
+    }
+
+    // Finishes a call whose compiled code failed a guard, by rebuilding its
+    // frame from the values the guard saved and interpreting the rest.
+    fn deoptimize(&mut self, guard: &Guard, values: &[i64]) -> CallResult {
+        remarks::missed("deopt", &guard.func.name, Some(guard.index), &|| {
+            format!("left compiled code at {}", guard.func.instrs[guard.index].label.as_ref().unwrap())
+        });
+        // held until the call is done, since the code the guard is in may be
+        // freed once it's thrown away
+        let resume = guard.func.clone();
+        let from = guard.from.clone();
+        let index = guard.index;
+        self.invalidate(guard);
+        let mut env = Env::new();
+        for ((var, _), &value) in guard.vars.iter().zip(values) {
+            env.put(var_name(&resume, var), value);
+        }
+        if !self.eval_from(&resume, &mut env, index, from.as_deref()) {
+            panic!("Failed when calling function");
+        }
+        if self.tail_call.is_some() {
+            return CallResult {
+                has_value: TAIL_CALL,
+                value: 0,
+            };
+        }
+        env.get(RETURN_VAR).into()
+    }
+
+    // Throws away code whose speculation failed, unless it's already been
+    // replaced. Calls go back through the interpreter, which profiles the
+    // function again before it's recompiled, this time without guards so a
+    // function whose profile misled once doesn't keep getting recompiled.
+    fn invalidate(&mut self, guard: &Guard) {
+        let is_current = match self.asm_map.get(&guard.func_idx) {
+            Some(func_asm) => func_asm.guards.iter().any(|g| ptr::eq(&**g, guard)),
+            None => false,
+        };
+        if is_current {
+            let func_asm = self.asm_map.remove(&guard.func_idx).unwrap();
+            self.retired.push(func_asm);
+            self.deopted.insert(guard.func_idx);
+            self.profile_map.insert(guard.func_idx, 0);
+            self.link(guard.func_idx);
+        }
+    }
+
+    // Calls with constant arguments go to a copy of the callee specialized
+    // to those constants, which is compiled on its own once it gets hot.
+    fn callee_index(&mut self, name: &str, signature: Signature) -> i64 {
+        let func_idx = *self.index_map.get(name).unwrap();
+        let callee = &self.program.functions[func_idx as usize];
+        if !specialize::is_profitable(callee, &signature) {
+            return func_idx;
+        }
+        let key = (func_idx, signature);
+        if let Some(&spec_idx) = self.spec_map.get(&key) {
+            return spec_idx;
+        }
+        let num_clones = self.spec_map.keys().filter(|(idx, _)| *idx == func_idx).count();
+        if num_clones == specialize::MAX_CLONES {
+            remarks::missed("spec", name, None, &|| {
+                format!("{} already has {} clones", name, specialize::MAX_CLONES)
+            });
+            return func_idx;
+        }
+        let spec_idx = self.profile_map.len() as i64;
+        self.bril_map.insert(spec_idx, callee);
+        self.profile_map.insert(spec_idx, 0);
+        self.signatures.insert(spec_idx, key.1.clone());
+        if self.pure_funcs.contains(&func_idx) {
+            self.pure_funcs.insert(spec_idx);
+        }
+        self.spec_map.insert(key, spec_idx);
+        spec_idx
+    }
+}
+
+impl Job {
+    fn compile(&self) -> Result<Compiled, String> {
+        let func_idx = self.func_idx;
+        let tier = self.tier;
+        let mut optimized = self.func.clone();
+        // callees are hot once they'd be worth compiling on their own
+        let is_hot = |name: &str| self.hot.contains(name);
+        let ctx = Context {
+            program: &self.program,
+            is_hot: &is_hot,
+        };
+        self.pipeline.run_function(&mut optimized, &ctx)?;
+        // Blocks the interpreter never reached, in a function it has run often
+        // enough, are compiled as guards instead, unless a guard in the
+        // function has failed before. The function they're in is kept for as
+        // long as the interpreter, to resume in.
+        let mut cold = HashSet::new();
+        if let Some(label_profile_map) = &self.profile {
+            let count = |label: &str| label_profile_map.get(label).map(|p| p.0);
+            let edge = |from: &str, to: &str| match label_profile_map.get(to) {
+                Some(p) => p.1.get(from).cloned().unwrap_or(0),
+                None => 0,
+            };
+            layout::run(&mut optimized, &count, &edge);
+            if self.speculate {
+                for instr in &optimized.instrs {
+                    if let Some(l) = &instr.label {
+                        if count(l) == Some(0) {
+                            cold.insert(l.clone());
+                        }
                     }
                 }
             }
         }
+        let resume = if cold.is_empty() {
+            None
+        } else {
+            Some(Arc::new(optimized.clone()))
+        };
+        let bril_func = resume.as_deref().unwrap_or(&optimized);
+        // the block leading to each cold one, where only one does
+        let mut cold_from = HashMap::new();
+        if !cold.is_empty() {
+            let cfg = Cfg::new(bril_func);
+            for (b, block) in cfg.blocks.iter().enumerate() {
+                if let (Some(label), [p]) = (&block.label, &cfg.preds[b][..]) {
+                    let from = match &cfg.blocks[*p].label {
+                        Some(from) => from.as_str(),
+                        None if *p == 0 => ENTRY,
+                        None => continue,
+                    };
+                    cold_from.insert(label.clone(), from.to_string());
+                }
+            }
+        }
+        let consts = specialize::constants(bril_func);
+        let mut asm = dynasmrt::x64::Assembler::new().unwrap();
+        let mut guards = Vec::new();
 
-        // 8 * #variables, rounded up to a multiple of 16
-        let num_bytes = 16 * (num_vars / 2);
+        let alloc = match tier {
+            Tier::Baseline => regalloc::spill_all(bril_func),
+            Tier::Optimized => regalloc::allocate(bril_func, &CALLEE_SAVED, &CALLER_SAVED),
+        };
+        // [rbp - 8] holds the interpreter, followed by the spill slots and a
+        // slot per allocatable register to save it in
+        let stack = |slot: usize| Loc::Stack(8 * (slot as i32 + 2));
+        let save_slot = |reg: u8| {
+            let k = CALLEE_SAVED.iter().chain(&CALLER_SAVED).position(|&r| r == reg).unwrap();
+            stack(alloc.num_slots + k)
+        };
+        let mut var_locs = HashMap::<&str, Loc>::new();
+        for (var, location) in &alloc.locations {
+            let loc = match *location {
+                Location::Reg(reg) => Loc::Reg(reg),
+                Location::Slot(slot) => stack(slot),
+            };
+            var_locs.insert(var, loc);
+        }
+        // Caller-saved registers holding values at a call are saved before it,
+        // so arguments in them are read back from their save slots.
+        let arg_loc = |var: &str| match var_locs[var] {
+            Loc::Reg(reg) if CALLER_SAVED.contains(&reg) => save_slot(reg),
+            loc => loc,
+        };
+        let clobbered = |i: usize| -> Vec<(u8, Loc)> {
+            alloc
+                .live_at(i)
+                .filter_map(|interval| match var_locs[&*interval.var] {
+                    Loc::Reg(reg) if CALLER_SAVED.contains(&reg) => Some((reg, save_slot(reg))),
+                    _ => None,
+                })
+                .collect()
+        };
+        // values that die at the call, or that it defines, aren't restored
+        let restored = |i: usize, dest: Option<&String>| -> Vec<(u8, Loc)> {
+            alloc
+                .live_at(i)
+                .filter(|interval| interval.end > i && Some(&interval.var) != dest)
+                .filter_map(|interval| match var_locs[&*interval.var] {
+                    Loc::Reg(reg) if CALLER_SAVED.contains(&reg) => Some((reg, save_slot(reg))),
+                    _ => None,
+                })
+                .collect()
+        };
+        let callee_saved: Vec<(u8, Loc)> = alloc
+            .registers()
+            .into_iter()
+            .filter(|reg| CALLEE_SAVED.contains(reg))
+            .map(|reg| (reg, save_slot(reg)))
+            .collect();
+        let mut labels = HashMap::<&str, dynasmrt::DynamicLabel>::new();
+        let mut callees = Vec::new();
+        let mut relocs = Vec::new();
+        let mut in_cold_block = false;
 
-        let first_start = self.asm.offset();
+        // spill slots plus save slots, rounded up to a multiple of 16
+        let num_bytes = 16 * ((alloc.num_slots + CALLEE_SAVED.len() + CALLER_SAVED.len() + 2) / 2) as i32;
+        // Every way into the code counts a frame in, before it can call
+        // anything, and every way out counts it back out.
+        let count_frames = self.count_calls;
+        let frames = if count_frames { Some(Box::new(Cell::new(0))) } else { None };
 
-        if let Some(lbl) = label {
-            // prologue
-            dynasm!(self.asm
+        // extern "C" fn(ctx, frame) -> CallResult, which loads the values
+        // live at the label from the frame buffer and jumps there
+        let mut osr = None;
+        if let Some(lbl) = &self.label {
+            let entry = asm.offset();
+            dynasm!(asm
                 ; push rbp
                 ; mov rbp, rsp
                 ; sub rsp, num_bytes
                 ; mov [rbp - 8], rdi
             );
+            if count_frames {
+                count_frame(&mut asm, &mut relocs, 1);
+            }
+            save(&mut asm, &callee_saved);
+            let pos = Interpreter::find_label(bril_func, lbl).unwrap();
+            let vars: Vec<String> = alloc.live_at(pos).map(|interval| interval.var.clone()).collect();
+            for (k, var) in vars.iter().enumerate() {
+                dynasm!(asm ; mov rax, [rsi + 8 * k as i32]);
+                store(&mut asm, var_locs[&**var], RAX);
+            }
+            let dyn_label = get_dyn_label(&mut asm, &mut labels, lbl);
+            dynasm!(asm ; jmp =>dyn_label);
+            osr = Some((entry, vars));
+        }
 
-            if let Some(interp_env) = env {
-                for (k, v) in var_offsets.iter() {
-                    if let Some(var) = interp_env.get(&k) {
-                        dynasm!(self.asm
-                            ; mov rax, QWORD var
-                            ; mov [rbp - v], rax
-                        );
-                    }
-                }
-            };
+        let params = bril_func.args.clone().unwrap_or_default();
+        let num_reg_args = params.len().min(ARG_REGS.len());
+        let num_stack_args = params.len() - num_reg_args;
+        let native_label = asm.new_dynamic_label();
 
-            let dyn_label = get_dyn_label(&mut self.asm, &mut labels, lbl);
-            dynasm!(self.asm
-                ; jmp =>dyn_label
-            );
+        // extern "C" fn(ctx, args, num_args) -> CallResult, which moves the
+        // arguments where the internal calling convention wants them
+        // The trampoline counts as a frame of its own, as a callee that the
+        // code tail calls returns to it.
+        let start = asm.offset();
+        dynasm!(asm
+            ; push rbp
+            ; mov rbp, rsp
+        );
+        if count_frames {
+            count_frame(&mut asm, &mut relocs, 1);
         }
+        dynasm!(asm ; mov rax, rsi);
+        if num_stack_args % 2 == 1 {
+            dynasm!(asm ; sub rsp, 8);
+        }
+        for i in (num_reg_args..params.len()).rev() {
+            dynasm!(asm ; push QWORD [rax + 8 * i as i32]);
+        }
+        for (i, &reg) in ARG_REGS[..num_reg_args].iter().enumerate() {
+            dynasm!(asm ; mov Rq(reg), [rax + 8 * i as i32]);
+        }
+        dynasm!(asm ; call =>native_label);
+        if count_frames {
+            count_frame(&mut asm, &mut relocs, -1);
+        }
+        dynasm!(asm
+            ; mov rsp, rbp
+            ; pop rbp
+            ; ret
+        );
 
-        let start = self.asm.offset();
-
-        // prologue, again!
-        dynasm!(self.asm
+        // Entry for jitted callers: the first arguments come in ARG_REGS,
+        // the rest on the stack above the return address.
+        let native = asm.offset();
+        dynasm!(asm
+            ; =>native_label
             ; push rbp
             ; mov rbp, rsp
             ; sub rsp, num_bytes
             ; mov [rbp - 8], rdi
         );
+        if count_frames {
+            count_frame(&mut asm, &mut relocs, 1);
+        }
+        save(&mut asm, &callee_saved);
+        // through the stack, since a parameter may live in another's register
+        for &reg in &ARG_REGS[..num_reg_args] {
+            dynasm!(asm ; push Rq(reg));
+        }
+        for param in params[..num_reg_args].iter().rev() {
+            pop(&mut asm, var_locs.get::<str>(&param.name).cloned());
+        }
+        for (i, param) in params.iter().enumerate().skip(num_reg_args) {
+            if let Some(&loc) = var_locs.get::<str>(&param.name) {
+                dynasm!(asm ; mov rax, [rbp + 16 + 8 * (i - num_reg_args) as i32]);
+                store(&mut asm, loc, RAX);
+            }
+        }
 
-        let num_args: i32 = if let Some(args) = &bril_func.args {
-            args.len() as _
-        } else {
-            0
-        };
-
-        dynasm!(self.asm ; mov rsi, [rsi]);
-
-        for i in 0..num_args {
-            dynasm!(self.asm
-                ; mov rax, [rsi + 8*i]
-                ; mov [rbp - 8*(i + 2)], rax
+        let counter = Box::new(Cell::new(0));
+        if let (Tier::Baseline, Some(opt_n)) = (tier, self.opt_n) {
+            mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Counter);
+            dynasm!(asm
+                ; add QWORD [rax], 1
+                ; cmp QWORD [rax], opt_n as i32
+                ; jl >counted
+                ; mov rdi, [rbp - 8]
+            );
+            mov_reloc(&mut asm, &mut relocs, RSI, Reloc::Index);
+            mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Runtime(Runtime::TierUp));
+            dynasm!(asm
+                ; call rax
+                ; counted:
             );
+        } else if self.count_calls {
+            mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Counter);
+            dynasm!(asm ; add QWORD [rax], 1);
         }
 
-        for inst in &bril_func.instrs {
+        let body = asm.new_dynamic_label();
+        dynasm!(asm ; =>body);
+
+        for (i, inst) in bril_func.instrs.iter().enumerate() {
+            if in_cold_block && inst.label.is_none() {
+                continue;
+            }
+            let next_label = bril_func.instrs.get(i + 1).and_then(|next| next.label.as_ref());
             match &inst.op {
                 Some(OpCode::BinOp(op)) => {
                     if let (Some(args), Some(dest)) = (&inst.args, &inst.dest) {
                         if let (Some(&a), Some(&b), Some(&d)) = (
-                            var_offsets.get::<str>(&args[0]),
-                            var_offsets.get::<str>(&args[1]),
-                            var_offsets.get::<str>(dest),
+                            var_locs.get::<str>(&args[0]),
+                            var_locs.get::<str>(&args[1]),
+                            var_locs.get::<str>(dest),
                         ) {
-                            dynasm!(self.asm ; mov rax, [rbp - a]);
+                            load(&mut asm, RAX, a);
+                            load(&mut asm, RCX, b);
                             match op.as_ref() {
                                 "add" => {
-                                    dynasm!(self.asm ; add rax, [rbp - b]);
+                                    dynasm!(asm ; add rax, rcx);
                                 }
                                 "sub" => {
-                                    dynasm!(self.asm ; sub rax, [rbp - b]);
+                                    dynasm!(asm ; sub rax, rcx);
                                 }
                                 "mul" => {
-                                    dynasm!(self.asm ; imul rax, [rbp - b]);
+                                    dynasm!(asm ; imul rax, rcx);
                                 }
                                 "div" => {
-                                    dynasm!(self.asm ; cqo ; idiv QWORD [rbp - b]);
+                                    dynasm!(asm ; cqo ; idiv rcx);
+                                }
+                                "shl" => {
+                                    dynasm!(asm ; shl rax, cl);
+                                }
+                                "shr" => {
+                                    dynasm!(asm ; sar rax, cl);
                                 }
                                 "eq" => {
-                                    dynasm!(self.asm ; cmp rax, [rbp - b] ; sete al ; movzx rax, al);
+                                    dynasm!(asm ; cmp rax, rcx ; sete al ; movzx rax, al);
                                 }
                                 "lt" => {
-                                    dynasm!(self.asm ; cmp rax, [rbp - b] ; setl al ; movzx rax, al);
+                                    dynasm!(asm ; cmp rax, rcx ; setl al ; movzx rax, al);
                                 }
                                 "gt" => {
-                                    dynasm!(self.asm ; cmp rax, [rbp - b] ; setg al ; movzx rax, al);
+                                    dynasm!(asm ; cmp rax, rcx ; setg al ; movzx rax, al);
                                 }
                                 "le" => {
-                                    dynasm!(self.asm ; cmp rax, [rbp - b] ; setle al ; movzx rax, al);
+                                    dynasm!(asm ; cmp rax, rcx ; setle al ; movzx rax, al);
                                 }
                                 "ge" => {
-                                    dynasm!(self.asm ; cmp rax, [rbp - b] ; setge al ; movzx rax, al);
+                                    dynasm!(asm ; cmp rax, rcx ; setge al ; movzx rax, al);
                                 }
                                 _ => {}
                             }
-                            dynasm!(self.asm ; mov [rbp - d], rax);
+                            store(&mut asm, d, RAX);
                         }
                     }
                 }
                 Some(OpCode::BinOpBool(op)) => {
                     if let (Some(args), Some(dest)) = (&inst.args, &inst.dest) {
                         if let (Some(&a), Some(&b), Some(&d)) = (
-                            var_offsets.get::<str>(&args[0]),
-                            var_offsets.get::<str>(&args[1]),
-                            var_offsets.get::<str>(dest),
+                            var_locs.get::<str>(&args[0]),
+                            var_locs.get::<str>(&args[1]),
+                            var_locs.get::<str>(dest),
                         ) {
-                            dynasm!(self.asm ; mov rax, [rbp - a]);
+                            load(&mut asm, RAX, a);
+                            load(&mut asm, RCX, b);
                             match op.as_ref() {
                                 "and" => {
-                                    dynasm!(self.asm ; and rax, [rbp - b]);
+                                    dynasm!(asm ; and rax, rcx);
                                 }
                                 "or" => {
-                                    dynasm!(self.asm ; or rax, [rbp - b]);
+                                    dynasm!(asm ; or rax, rcx);
                                 }
                                 _ => {}
                             }
-                            dynasm!(self.asm ; mov [rbp - d], rax);
+                            store(&mut asm, d, RAX);
                         }
                     }
                 }
                 Some(OpCode::UnOpBool(op)) => {
                     if let (Some(args), Some(dest)) = (&inst.args, &inst.dest) {
                         if let (Some(&a), Some(&d)) = (
-                            var_offsets.get::<str>(&args[0]),
-                            var_offsets.get::<str>(dest),
+                            var_locs.get::<str>(&args[0]),
+                            var_locs.get::<str>(dest),
                         ) {
-                            dynasm!(self.asm ; mov rax, [rbp - a]);
-                            match op.as_ref() {
-                                "not" => {
-                                    dynasm!(self.asm ; xor rax, 1);
-                                }
-                                _ => {}
+                            load(&mut asm, RAX, a);
+                            if op == "not" {
+                                dynasm!(asm ; xor rax, 1);
                             }
-                            dynasm!(self.asm ; mov [rbp - d], rax);
+                            store(&mut asm, d, RAX);
                         }
                     }
                 }
                 Some(OpCode::Const) => {
                     if let Some(dest) = &inst.dest {
                         let value = inst.value.as_ref().unwrap();
-                        if let Some(&d) = var_offsets.get::<str>(dest) {
-                            dynasm!(self.asm
-                                ; mov rax, QWORD *value
-                                ; mov [rbp - d], rax
-                            );
+                        if let Some(&d) = var_locs.get::<str>(dest) {
+                            dynasm!(asm ; mov rax, QWORD *value);
+                            store(&mut asm, d, RAX);
                         }
                     }
                 }
@@ -343,152 +1556,254 @@ impl<'a> Interpreter<'a> {
                     if let Some(args) = &inst.args {
                         let name = &args[0];
                         let num_args = &args.len() - 1;
-                        let num_bytes = 256 + 16 * ((num_args + 1) / 2) as i32;
-                        dynasm!(self.asm
-                            ; sub rsp, num_bytes
-                            ; mov rdi, rsp
-                            ; mov rsi, num_args as i32
-                            ; mov rcx, QWORD Vec::<i64>::with_capacity as _
-                            ; call rcx
-                        );
-                        for i in 0..num_args {
-                            let var = &args[i + 1];
-                            let value = var_offsets.get::<str>(var).unwrap();
-                            dynasm!(self.asm
-                                ; mov rdi, rsp
-                                ; mov rsi, [rbp - value]
-
-                                ; mov rcx, QWORD Vec::<i64>::push as _
-                                ; call rcx
+                        let is_tail_call = bril_func.tail_call(i).is_some();
+                        if is_tail_call && *name == bril_func.name {
+                            // self tail call: overwrite the arguments in
+                            // place and start over without a new frame
+                            for arg in &args[1..] {
+                                push(&mut asm, var_locs[&**arg]);
+                            }
+                            for param in params.iter().rev() {
+                                pop(&mut asm, var_locs.get::<str>(&param.name).cloned());
+                            }
+                            dynasm!(asm ; jmp =>body);
+                            continue;
+                        }
+                        let k = callees.len();
+                        callees.push(Callee {
+                            name: name.clone(),
+                            signature: specialize::signature(inst, &consts),
+                        });
+                        if is_tail_call {
+                            // Once the callee is compiled, jump to it with this
+                            // frame gone. Until then, or if some arguments would
+                            // need this frame, hand the call back to handle_call.
+                            let slow = asm.new_dynamic_label();
+                            if num_args <= ARG_REGS.len() {
+                                mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Target(k));
+                                mov_reloc(&mut asm, &mut relocs, RCX, Reloc::Stub(k));
+                                dynasm!(asm
+                                    ; cmp rax, rcx
+                                    ; je =>slow
+                                );
+                                for arg in &args[1..] {
+                                    push(&mut asm, var_locs[&**arg]);
+                                }
+                                for &reg in ARG_REGS[..num_args].iter().rev() {
+                                    dynasm!(asm ; pop Rq(reg));
+                                }
+                                if count_frames {
+                                    count_frame(&mut asm, &mut relocs, -1);
+                                }
+                                restore(&mut asm, &callee_saved);
+                                dynasm!(asm
+                                    ; mov rdi, [rbp - 8]
+                                    ; mov rsp, rbp
+                                    ; pop rbp
+                                    ; jmp rax
+                                );
+                            }
+                            dynasm!(asm
+                                ; =>slow
+                                ; sub rsp, 16 * ((num_args as i32 + 1) / 2)
+                            );
+                            for (i, arg) in args[1..].iter().enumerate() {
+                                load(&mut asm, RAX, var_locs[&**arg]);
+                                dynasm!(asm ; mov [rsp + 8 * i as i32], rax);
+                            }
+                            dynasm!(asm ; mov rdi, [rbp - 8]);
+                            mov_reloc(&mut asm, &mut relocs, RSI, Reloc::Callee(k));
+                            dynasm!(asm
+                                ; mov rdx, rsp
+                                ; mov rcx, QWORD num_args as i64
+                            );
+                            mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Runtime(Runtime::SetTailCall));
+                            dynasm!(asm
+                                ; call rax
+                                ; mov rax, TAIL_CALL as i32
                             );
+                            epilogue(&mut asm, &mut relocs, &callee_saved, count_frames);
+                            continue;
                         }
-                        dynasm!(self.asm
-                            ; mov rax, QWORD Interpreter::handle_call as _
+                        let saved = clobbered(i);
+                        save(&mut asm, &saved);
+                        // arguments past ARG_REGS go on the stack, last first,
+                        // padded to keep rsp 16-byte aligned at the call
+                        let num_stack_args = num_args.saturating_sub(ARG_REGS.len());
+                        let stack_bytes = 16 * ((num_stack_args as i32 + 1) / 2);
+                        if num_stack_args % 2 == 1 {
+                            dynasm!(asm ; sub rsp, 8);
+                        }
+                        for arg in args[1 + ARG_REGS.len().min(num_args)..].iter().rev() {
+                            push(&mut asm, arg_loc(arg));
+                        }
+                        for (k, arg) in args[1..].iter().take(ARG_REGS.len()).enumerate() {
+                            load(&mut asm, ARG_REGS[k], arg_loc(arg));
+                        }
+                        dynasm!(asm ; mov rdi, [rbp - 8]);
+                        mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Target(k));
+                        dynasm!(asm ; call rax);
+                        if stack_bytes > 0 {
+                            dynasm!(asm ; add rsp, stack_bytes);
+                        }
+                        // the callee may have left a tail call to make here
+                        dynasm!(asm
+                            ; cmp rax, TAIL_CALL as i32
+                            ; jne >done
                             ; mov rdi, [rbp - 8]
-                            ; mov rsi, QWORD *self.index_map.get::<str>(name).unwrap()
-                            ; mov rdx, rsp
+                        );
+                        mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Runtime(Runtime::FinishTailCall));
+                        dynasm!(asm
                             ; call rax
+                            ; done:
                         );
+                        restore(&mut asm, &restored(i, inst.dest.as_ref()));
                         if let Some(dest) = &inst.dest {
-                            let value = var_offsets.get::<str>(dest).unwrap();
-                            dynasm!(self.asm
-                                ; mov [rbp - value], rdx
-                            );
+                            store(&mut asm, var_locs[&**dest], RDX);
                         }
-                        dynasm!(self.asm
-                            ; add rsp, num_bytes
-                        );
                     }
                 }
                 Some(OpCode::Print) => {
                     if let Some(args) = &inst.args {
+                        save(&mut asm, &clobbered(i));
                         for arg in args {
-                            if let Some(&a) = var_offsets.get::<str>(arg) {
-                                dynasm!(self.asm
-                                    ; mov rdi, [rbp - a]
-                                    ; mov rax, QWORD print_int as _);
-                                // if let Some(&inst_type) = var_types.get(arg).as_ref() {
-                                //     match inst_type.as_ref() {
-                                //         "int" => { dynasm!(self.asm ; mov rax, QWORD print_int as _); }
-                                //         "bool" => { dynasm!(self.asm ; mov rax, QWORD print_bool as _); }
-                                //         _ => { }
-                                //     }
-                                // }
-                                dynasm!(self.asm ; call rax);
+                            if var_locs.contains_key::<str>(arg) {
+                                load(&mut asm, RDI, arg_loc(arg));
+                                mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Runtime(Runtime::PrintInt));
+                                dynasm!(asm ; call rax);
                             }
                         }
-                        dynasm!(self.asm
-                            ; mov rax, QWORD print_newline as _
-                            ; call rax
-                        );
+                        mov_reloc(&mut asm, &mut relocs, RAX, Reloc::Runtime(Runtime::PrintNewline));
+                        dynasm!(asm ; call rax);
+                        restore(&mut asm, &restored(i, None));
                     }
                 }
+                Some(OpCode::Phi) => {
+                    panic!("Phi nodes must be removed before compilation");
+                }
                 Some(OpCode::Nop) => {
-                    dynasm!(self.asm ; nop);
+                    dynasm!(asm ; nop);
                 }
                 Some(OpCode::Jmp) => {
                     if let Some(args) = &inst.args {
-                        let dyn_label = get_dyn_label(&mut self.asm, &mut labels, &args[0]);
-                        dynasm!(self.asm ; jmp =>dyn_label);
+                        if next_label != Some(&args[0]) {
+                            let dyn_label = get_dyn_label(&mut asm, &mut labels, &args[0]);
+                            dynasm!(asm ; jmp =>dyn_label);
+                        }
                     }
                 }
                 Some(OpCode::Br) => {
                     if let Some(args) = &inst.args {
-                        if let Some(&b) = var_offsets.get::<str>(&args[0]) {
+                        if let Some(&b) = var_locs.get::<str>(&args[0]) {
                             let dyn_label_true =
-                                get_dyn_label(&mut self.asm, &mut labels, &args[1]);
+                                get_dyn_label(&mut asm, &mut labels, &args[1]);
                             let dyn_label_false =
-                                get_dyn_label(&mut self.asm, &mut labels, &args[2]);
-                            dynasm!(self.asm
-                                ; test [rbp - b], 1
-                                ; jne =>dyn_label_true
-                                ; jmp =>dyn_label_false
-                            );
+                                get_dyn_label(&mut asm, &mut labels, &args[2]);
+                            load(&mut asm, RAX, b);
+                            // whichever target comes next is reached by falling through
+                            if next_label == Some(&args[2]) {
+                                dynasm!(asm
+                                    ; test rax, 1
+                                    ; jne =>dyn_label_true
+                                );
+                            } else if next_label == Some(&args[1]) {
+                                dynasm!(asm
+                                    ; test rax, 1
+                                    ; je =>dyn_label_false
+                                );
+                            } else {
+                                dynasm!(asm
+                                    ; test rax, 1
+                                    ; jne =>dyn_label_true
+                                    ; jmp =>dyn_label_false
+                                );
+                            }
                         }
                     }
                 }
                 Some(OpCode::Ret) => {
                     if let Some(args) = &inst.args {
                         if !args.is_empty() {
-                            let offset = var_offsets.get::<str>(&args[0]).unwrap();
-                            dynasm!(self.asm
-                                ; mov rax, 1
-                                ; mov rdx, [rbp - offset]
-                            );
+                            dynasm!(asm ; mov rax, 1);
+                            load(&mut asm, RDX, var_locs[&*args[0]]);
                         } else {
-                            dynasm!(self.asm
+                            dynasm!(asm
                                 ; mov rax, 0
                                 ; mov rdx, 0
                             );
                         }
                     }
-                    dynasm!(self.asm
-                        ; mov rsp, rbp
-                        ; pop rbp
-                        ; ret
-                    );
+                    epilogue(&mut asm, &mut relocs, &callee_saved, count_frames);
                 }
                 Some(OpCode::Id) => {
                     if let (Some(args), Some(dest)) = (&inst.args, &inst.dest) {
                         if let (Some(&a), Some(&d)) = (
-                            var_offsets.get::<str>(&args[0]),
-                            var_offsets.get::<str>(dest),
+                            var_locs.get::<str>(&args[0]),
+                            var_locs.get::<str>(dest),
                         ) {
-                            dynasm!(self.asm ; mov rax, [rbp - a]);
-                            dynasm!(self.asm ; mov [rbp - d], rax);
+                            load(&mut asm, RAX, a);
+                            store(&mut asm, d, RAX);
                         }
                     }
                 }
                 None => {
                     if let Some(label) = &inst.label {
-                        let dyn_label = get_dyn_label(&mut self.asm, &mut labels, label);
-                        dynasm!(self.asm ; =>dyn_label);
+                        let dyn_label = get_dyn_label(&mut asm, &mut labels, label);
+                        dynasm!(asm ; =>dyn_label);
+                        in_cold_block = cold.contains(label);
+                        if in_cold_block {
+                            remarks::applied("deopt", &bril_func.name, Some(i), &|| {
+                                format!("guarded {} as never reached", label)
+                            });
+                            let guard = Box::new(Guard {
+                                func_idx,
+                                func: resume.clone().unwrap(),
+                                index: i,
+                                from: cold_from.get(label).cloned(),
+                                vars: live_vars(bril_func, &alloc, i)
+                                    .into_iter()
+                                    .map(|var| (var.to_string(), var_locs[var]))
+                                    .collect(),
+                            });
+                            deopt_exit(&mut asm, &mut relocs, &guard, guards.len(), &callee_saved, count_frames);
+                            guards.push(guard);
+                        }
                     }
                 }
             }
         }
 
         // epilogue
-        dynasm!(self.asm
-            ; mov rsp, rbp
-            ; pop rbp
-            ; mov rax, 0
-            ; ret
-        );
-        let mut asm_final = dynasmrt::x64::Assembler::new().unwrap();
-        mem::swap(&mut self.asm, &mut asm_final);
-        let code = asm_final.finalize().unwrap();
-        return (
-            AsmProgram {
-                code: code,
-                start: start,
-            },
-            first_start,
-        );
+        dynasm!(asm ; mov rax, 0);
+        epilogue(&mut asm, &mut relocs, &callee_saved, count_frames);
+        Ok(Compiled {
+            func_idx,
+            label: self.label.clone(),
+            code: asm.finalize().unwrap().to_vec(),
+            start,
+            native,
+            guards,
+            tier,
+            counter,
+            frames,
+            osr,
+            callees,
+            relocs,
+            remarks: Vec::new(),
+            calls: self.calls,
+            profile: self.profile.clone(),
+            key: self.key,
+        })
     }
+}
 
-    pub fn eval_program(&mut self, args: Vec<i64>) {
-        self.handle_call(*self.index_map.get("main").unwrap(), args);
+impl<'a> Interpreter<'a> {
+    pub fn eval_program(&mut self, args: Vec<i64>) -> Option<i64> {
+        let result = self.handle_call(*self.index_map.get("main").unwrap(), args);
+        if let Some(memo) = &self.memo {
+            memo.report();
+        }
+        result
     }
 
     pub fn find_label(func: &Function, label: &str) -> Option<usize> {
@@ -508,16 +1823,43 @@ impl<'a> Interpreter<'a> {
     }
 
     pub fn eval_func(&mut self, func: &'a Function, env: &mut Env<'a>) -> bool {
-        let mut i = 0;
+        self.eval_from(func, env, 0, Some(ENTRY))
+    }
+
+    // Interprets func from instruction i on, which is how deoptimized calls
+    // pick up where their compiled code left off. `from` is the block control
+    // comes from, if it's known, for the edge into the first label.
+    //
+    // Nothing the interpreter keeps borrows from func, as the copy a
+    // deoptimized call resumes in only lives as long as the call.
+    pub fn eval_from<'f>(&mut self, func: &'f Function, env: &mut Env<'f>, i: usize, from: Option<&'f str>) -> bool {
+        let mut i = i;
+        let mut block = from;
         while i < func.instrs.len() {
             let instr = &func.instrs[i];
-            let action = self.eval_instr(&instr, &func, env);
+            if let Some(label) = &instr.label {
+                if let Some(from) = block {
+                    self.count_edge(&func.name, from, label);
+                }
+                block = Some(label);
+            }
+            if let Some(name) = func.tail_call(i) {
+                // the frame is dropped here and handle_call starts the callee
+                let func_idx = *self.index_map.get::<str>(name).unwrap();
+                let mut args = Vec::new();
+                for arg in &instr.args.as_ref().unwrap()[1..] {
+                    args.push(env.get(arg).unwrap());
+                }
+                self.set_tail_call(func_idx, args);
+                return true;
+            }
+            let action = self.eval_instr(instr, func, env);
             match action {
                 Ok(Action::Next) => {
                     i += 1;
                 }
                 Ok(Action::Jump(label)) => {
-                    match Interpreter::find_label(&func, label) {
+                    match Interpreter::find_label(func, label) {
                         Some(v) => {
                             i = v;
                         }
@@ -537,28 +1879,55 @@ impl<'a> Interpreter<'a> {
         true
     }
 
-    pub fn eval_instr(
+    // Only edges from the function's own labels are counted, as the copy a
+    // deoptimized call resumes in has labels of its own that don't last.
+    fn count_edge(&mut self, func_name: &str, from: &str, to: &str) {
+        if let Some(label_profile_map) = self.label_map.get_mut(func_name) {
+            let from = match label_profile_map.get_key_value(from) {
+                Some((&from, _)) => from,
+                None => return,
+            };
+            if let Some(label_profile_data) = label_profile_map.get_mut(to) {
+                *label_profile_data.preds.entry(from).or_insert(0) += 1;
+            }
+        }
+    }
+
+    pub fn eval_instr<'f>(
         &mut self,
-        instr: &'a Instruction,
-        func: &'a Function,
-        env: &mut Env<'a>,
-    ) -> Result<Action, &str> {
+        instr: &'f Instruction,
+        func: &'f Function,
+        env: &mut Env<'f>,
+    ) -> Result<Action<'f>, &'static str> {
         if let Some(label) = &instr.label {
+            // the label as the profile keeps it, which outlives func
+            let mut hot_label = None;
             if let Some(label_profile_map) = self.label_map.get_mut::<str>(&func.name) {
-                if let Some(label_profile_data) = label_profile_map.get_mut::<str>(&label) {
-                    *label_profile_data += 1;
+                if let Some(label_profile_data) = label_profile_map.get_mut::<str>(label) {
+                    label_profile_data.count += 1;
                 };
-                if let Some(label_profile_data) = label_profile_map.get::<str>(label) {
-                    if self.osr && *label_profile_data >= self.osr_n {
-                        let func_idx = self.index_map.get::<str>(&func.name).unwrap();
-                        let return_val = self.handle_osr(env, *func_idx, label);
-                        if let Some(val) = return_val {
-                            env.put(RETURN_VAR, val);
-                        }
-                        return Ok(Action::Return);
+                if let Some((&label, label_profile_data)) = label_profile_map.get_key_value::<str>(label) {
+                    if self.osr && label_profile_data.count >= self.osr_n {
+                        hot_label = Some(label);
                     }
                 }
             };
+            if let Some(label) = hot_label {
+                let func_idx = *self.index_map.get::<str>(&func.name).unwrap();
+                // a deoptimized frame runs an optimized copy that OSR can't
+                // enter
+                if !ptr::eq(self.bril_map[&func_idx], func)
+                    || !self.is_loop_header(func_idx, label)
+                    || !self.has_osr_entry(func_idx, label)
+                {
+                    return Ok(Action::Next);
+                }
+                let return_val = self.handle_osr(env, func_idx, label);
+                if let Some(val) = return_val {
+                    env.put(RETURN_VAR, val);
+                }
+                return Ok(Action::Return);
+            }
             return Ok(Action::Next);
         };
         match instr.op.as_ref().unwrap_or(&Op::Nop) {
@@ -591,6 +1960,8 @@ impl<'a> Interpreter<'a> {
                     "mul" => env.put(dest, val1 * val2),
                     "sub" => env.put(dest, val1 - val2),
                     "div" => env.put(dest, val1 / val2),
+                    "shl" => env.put(dest, val1.wrapping_shl(val2 as u32)),
+                    "shr" => env.put(dest, val1.wrapping_shr(val2 as u32)),
                     "le" => env.put(dest, (val1 <= val2) as i64),
                     "lt" => env.put(dest, (val1 < val2) as i64),
                     "gt" => env.put(dest, (val1 > val2) as i64),
@@ -633,7 +2004,7 @@ impl<'a> Interpreter<'a> {
             Op::Print => {
                 let instr_args = &(instr.args).as_ref().unwrap();
                 for arg in *instr_args {
-                    print!("{} ", env.get(&arg).unwrap());
+                    print!("{} ", env.get(arg).unwrap());
                 }
                 println!();
                 Ok(Action::Next)
@@ -641,7 +2012,7 @@ impl<'a> Interpreter<'a> {
 
             Op::Jmp => {
                 let label = &instr.args.as_ref().unwrap()[0];
-                Ok(Action::Jump(&label))
+                Ok(Action::Jump(label))
             }
 
             Op::Br => {
@@ -661,39 +2032,88 @@ impl<'a> Interpreter<'a> {
                 let func_idx = self.index_map.get::<str>(name).unwrap();
                 let mut args = Vec::new();
                 for arg in &instr_args[1..] {
-                    args.push(env.get(&arg).unwrap());
+                    args.push(env.get(arg).unwrap());
                 }
                 let result = self.handle_call(*func_idx, args);
-                match &instr.dest {
-                    Some(var) => env.put(&var, result.unwrap()),
-                    None => (),
-                };
+                if let Some(var) = &instr.dest {
+                    env.put(var, result.unwrap());
+                }
                 Ok(Action::Next)
             }
 
             Op::Ret => {
                 let instr_args = &(instr.args).as_ref().unwrap();
-                if instr_args.len() > 0 {
+                if !instr_args.is_empty() {
                     let return_val = env.get(&instr_args[0]);
-                    env.put(&RETURN_VAR, return_val.unwrap());
+                    env.put(RETURN_VAR, return_val.unwrap());
                 }
                 Ok(Action::Return)
             }
 
+            Op::Phi => Err("Phi nodes must be removed before execution"),
+
             Op::Nop => Ok(Action::Next),
         }
     }
 }
 
-fn print_int(i: i64) {
-    print!("{} ", i);
+// Runtime helpers called from jitted code. `ctx` is the interpreter the code
+// was entered with; arguments come as a pointer to them and their number.
+extern "C" fn handle_call(ctx: *mut Interpreter, func_idx: i64, args: *const i64, num_args: usize) -> CallResult {
+    let interp = unsafe { &mut *ctx };
+    let args = unsafe { slice::from_raw_parts(args, num_args) }.to_vec();
+    interp.handle_call(func_idx, args).into()
+}
+
+extern "C" fn set_tail_call(ctx: *mut Interpreter, func_idx: i64, args: *const i64, num_args: usize) {
+    let interp = unsafe { &mut *ctx };
+    let args = unsafe { slice::from_raw_parts(args, num_args) }.to_vec();
+    interp.set_tail_call(func_idx, args);
+}
+
+// Adds delta to the code's count of its frames, in r11 so as to leave a
+// CallResult in rax and rdx, and arguments, alone.
+fn count_frame(asm: &mut dynasmrt::x64::Assembler, relocs: &mut Vec<(usize, Reloc)>, delta: i32) {
+    mov_reloc(asm, relocs, R11, Reloc::Frames);
+    dynasm!(asm ; add QWORD [r11], delta);
+}
+
+// Rewrites the immediate of the `mov reg, QWORD imm` at each offset.
+fn patch(code: &mut [u8], values: &[(usize, i64)]) {
+    for &(site, value) in values {
+        code[site + 2..site + 10].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+// Loads what reloc stands for into reg, once the code is installed.
+fn mov_reloc(asm: &mut dynasmrt::x64::Assembler, relocs: &mut Vec<(usize, Reloc)>, reg: u8, reloc: Reloc) {
+    relocs.push((asm.offset().0, reloc));
+    dynasm!(asm ; mov Rq(reg), QWORD 0);
+}
+
+extern "C" fn tier_up(ctx: *mut Interpreter, func_idx: i64) {
+    let interp = unsafe { &mut *ctx };
+    interp.tier_up(func_idx);
 }
 
-fn print_bool(b: bool) {
-    print!("{} ", b);
+extern "C" fn finish_tail_call(ctx: *mut Interpreter) -> CallResult {
+    let interp = unsafe { &mut *ctx };
+    let (func_idx, args) = interp.tail_call.take().unwrap();
+    interp.handle_call(func_idx, args).into()
 }
 
-fn print_newline() {
+extern "C" fn deoptimize(ctx: *mut Interpreter, guard: *const Guard, values: *const i64) -> CallResult {
+    let interp = unsafe { &mut *ctx };
+    let guard = unsafe { &*guard };
+    let values = unsafe { slice::from_raw_parts(values, guard.vars.len()) };
+    interp.deoptimize(guard, values)
+}
+
+extern "C" fn print_int(i: i64) {
+    print!("{} ", i);
+}
+
+extern "C" fn print_newline() {
     println!()
 }
 
@@ -703,10 +2123,162 @@ fn get_dyn_label<'a>(
     label: &'a str,
 ) -> dynasmrt::DynamicLabel {
     if let Some(&dyn_label) = labels.get(label) {
-        return dyn_label;
+        dyn_label
     } else {
         let dyn_label = asm.new_dynamic_label();
         labels.insert(label, dyn_label);
-        return dyn_label;
+        dyn_label
+    }
+}
+
+// Where a variable lives in jitted code: a register, or a slot at [rbp - off].
+#[derive(Clone, Copy, Deserialize, Serialize)]
+enum Loc {
+    Reg(u8),
+    Stack(i32),
+}
+
+static RAX: u8 = 0;
+static RCX: u8 = 1;
+static RDX: u8 = 2;
+static RSI: u8 = 6;
+static RDI: u8 = 7;
+static R11: u8 = 11;
+
+// rax, rcx, rdx, rsi and rdi are left as scratch for the code generator.
+static CALLEE_SAVED: [u8; 5] = [3, 12, 13, 14, 15];
+static CALLER_SAVED: [u8; 4] = [8, 9, 10, 11];
+
+// Internal calling convention between jitted functions: the interpreter in
+// rdi, arguments in these and then on the stack, and a CallResult back in
+// rax/rdx.
+static ARG_REGS: [u8; 5] = [6, 2, 1, 8, 9];
+
+fn load(asm: &mut dynasmrt::x64::Assembler, reg: u8, loc: Loc) {
+    match loc {
+        Loc::Reg(r) if r == reg => {}
+        Loc::Reg(r) => {
+            dynasm!(asm ; mov Rq(reg), Rq(r));
+        }
+        Loc::Stack(off) => {
+            dynasm!(asm ; mov Rq(reg), [rbp - off]);
+        }
+    }
+}
+
+fn store(asm: &mut dynasmrt::x64::Assembler, loc: Loc, reg: u8) {
+    match loc {
+        Loc::Reg(r) if r == reg => {}
+        Loc::Reg(r) => {
+            dynasm!(asm ; mov Rq(r), Rq(reg));
+        }
+        Loc::Stack(off) => {
+            dynasm!(asm ; mov [rbp - off], Rq(reg));
+        }
+    }
+}
+
+fn push(asm: &mut dynasmrt::x64::Assembler, loc: Loc) {
+    match loc {
+        Loc::Reg(r) => {
+            dynasm!(asm ; push Rq(r));
+        }
+        Loc::Stack(off) => {
+            dynasm!(asm ; push QWORD [rbp - off]);
+        }
+    }
+}
+
+fn pop(asm: &mut dynasmrt::x64::Assembler, loc: Option<Loc>) {
+    match loc {
+        Some(Loc::Reg(r)) => {
+            dynasm!(asm ; pop Rq(r));
+        }
+        Some(Loc::Stack(off)) => {
+            dynasm!(asm ; pop QWORD [rbp - off]);
+        }
+        None => {
+            dynasm!(asm ; add rsp, 8);
+        }
+    }
+}
+
+fn save(asm: &mut dynasmrt::x64::Assembler, regs: &[(u8, Loc)]) {
+    for &(reg, slot) in regs {
+        store(asm, slot, reg);
+    }
+}
+
+fn restore(asm: &mut dynasmrt::x64::Assembler, regs: &[(u8, Loc)]) {
+    for &(reg, slot) in regs {
+        load(asm, reg, slot);
+    }
+}
+
+// Returns with whatever is in rax/rdx, after restoring the caller's registers.
+fn epilogue(
+    asm: &mut dynasmrt::x64::Assembler,
+    relocs: &mut Vec<(usize, Reloc)>,
+    callee_saved: &[(u8, Loc)],
+    count_frames: bool,
+) {
+    if count_frames {
+        count_frame(asm, relocs, -1);
+    }
+    restore(asm, callee_saved);
+    dynasm!(asm
+        ; mov rsp, rbp
+        ; pop rbp
+        ; ret
+    );
+}
+
+// Variables live at instruction i, in the order a guard saves them.
+fn live_vars<'a>(func: &'a Function, alloc: &Allocation, i: usize) -> Vec<&'a str> {
+    let live: HashSet<&str> = alloc.live_at(i).map(|interval| &*interval.var).collect();
+    let mut vars: Vec<&'a str> = func
+        .args
+        .iter()
+        .flatten()
+        .map(|param| &*param.name)
+        .chain(func.instrs.iter().filter_map(|instr| instr.dest.as_deref()))
+        .filter(|var| live.contains(var))
+        .collect();
+    vars.sort();
+    vars.dedup();
+    vars
+}
+
+// The variable of func called var, as func's own string so it can key an Env.
+fn var_name<'a>(func: &'a Function, var: &str) -> &'a str {
+    func.args
+        .iter()
+        .flatten()
+        .map(|param| &*param.name)
+        .chain(func.instrs.iter().filter_map(|instr| instr.dest.as_deref()))
+        .find(|&name| name == var)
+        .unwrap()
+}
+
+// Leaves through deoptimize with the values the guard asks for, returning
+// whatever the interpreter makes of the rest of the call.
+fn deopt_exit(
+    asm: &mut dynasmrt::x64::Assembler,
+    relocs: &mut Vec<(usize, Reloc)>,
+    guard: &Guard,
+    k: usize,
+    callee_saved: &[(u8, Loc)],
+    count_frames: bool,
+) {
+    dynasm!(asm ; sub rsp, 16 * ((guard.vars.len() as i32 + 1) / 2));
+    for (k, &(_, loc)) in guard.vars.iter().enumerate() {
+        load(asm, RAX, loc);
+        dynasm!(asm ; mov [rsp + 8 * k as i32], rax);
     }
+    dynasm!(asm ; mov rdi, [rbp - 8]);
+    mov_reloc(asm, relocs, RSI, Reloc::Guard(k));
+    dynasm!(asm ; mov rdx, rsp);
+    mov_reloc(asm, relocs, RAX, Reloc::Runtime(Runtime::Deoptimize));
+    dynasm!(asm ; call rax);
+    epilogue(asm, relocs, callee_saved, count_frames);
 }
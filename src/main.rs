@@ -1,18 +1,23 @@
-#![feature(proc_macro_hygiene)]
-use compiler::Compiler;
 // use interpreter::Interpreter;
 use jit::Interpreter;
+use opt::manager::PassManager;
 
 use std::{
     env,
-    io::{self, Read},
     process,
+    sync::Arc,
 };
 
+mod code_cache;
 mod compiler;
+mod disk_cache;
+mod elf;
+#[allow(dead_code)]
 mod interpreter;
 mod jit;
+mod opt;
 mod program;
+mod validate;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -25,10 +30,29 @@ fn main() {
     let mut jit_n = 0;
     let mut osr = false;
     let mut osr_n = 0;
+    let mut pipeline = None;
+    let mut emit_opt = false;
+    let mut time_passes = false;
+    let mut verify = false;
+    let mut remarks: Option<&str> = None;
+    let mut memo = None;
+    // with -jit2, -jit compiles to the baseline tier and -jit2 calls of
+    // baseline code get the optimizing tier
+    let mut opt_n = None;
+    let mut background = false;
+    let mut code_limit = None;
+    let mut jit_cache = None;
+    let mut output = None;
+    let mut object_only = false;
+    let mut print_ret = false;
     let mut file_name = "";
     let mut cli_args = Vec::<i64>::new();
     let mut skip = false;
-    for i in 1..args.len() {
+    // `jit-bril validate prog.json` checks the pipeline instead of running it,
+    // and `jit-bril compile prog.json -o prog` compiles it ahead of time
+    let validate = args[1] == "validate";
+    let compile = args[1] == "compile";
+    for i in if validate || compile { 2 } else { 1 }..args.len() {
         if skip {
             skip = false;
             continue;
@@ -54,18 +78,97 @@ fn main() {
                     skip = true;
                 }
             }
+            "-jit2" => {
+                if i + 1 == args.len() {
+                    eprintln!("Expected argument for -jit2");
+                    process::exit(1);
+                } else {
+                    opt_n = Some(args[i + 1].parse().unwrap());
+                    skip = true;
+                }
+            }
+            "-code-cache" => {
+                if i + 1 == args.len() {
+                    eprintln!("Expected argument for -code-cache");
+                    process::exit(1);
+                } else {
+                    code_limit = Some(args[i + 1].parse().unwrap());
+                    skip = true;
+                }
+            }
+            "-jit-cache" => {
+                if i + 1 == args.len() {
+                    eprintln!("Expected argument for -jit-cache");
+                    process::exit(1);
+                } else {
+                    jit_cache = Some(&args[i + 1]);
+                    skip = true;
+                }
+            }
+            "-o" => {
+                if i + 1 == args.len() {
+                    eprintln!("Expected argument for -o");
+                    process::exit(1);
+                } else {
+                    output = Some(&args[i + 1]);
+                    skip = true;
+                }
+            }
+            // an object to link yourself, with the runtime's main in it
+            "-c" => {
+                object_only = true;
+            }
+            "-bg" => {
+                background = true;
+            }
+            "-O0" | "-O1" | "-O2" => {
+                pipeline = Some(PassManager::preset(args[i][2..].parse().unwrap()));
+            }
+            "-memo" => {
+                if i + 1 == args.len() {
+                    eprintln!("Expected argument for -memo");
+                    process::exit(1);
+                } else {
+                    memo = Some(args[i + 1].parse().unwrap());
+                    skip = true;
+                }
+            }
+            "-opt" => {
+                emit_opt = true;
+            }
+            "-ret" => {
+                print_ret = true;
+            }
+            "-time-passes" => {
+                time_passes = true;
+            }
+            "-verify" => {
+                verify = true;
+            }
+            arg if arg.starts_with("--remarks=") => {
+                remarks = Some(&arg["--remarks=".len()..]);
+                opt::remarks::enable();
+            }
+            arg if arg.starts_with("--passes=") => {
+                pipeline = Some(PassManager::parse(&arg["--passes=".len()..]));
+            }
             _ => {
-                if file_name == "" {
+                if file_name.is_empty() {
                     file_name = &args[i];
                 } else {
-                    println!("{}", args[i]);
-                    cli_args.push(args[i].parse().unwrap());
+                    match args[i].parse() {
+                        Ok(arg) => cli_args.push(arg),
+                        Err(_) => {
+                            eprintln!("Unknown argument {}", args[i]);
+                            process::exit(1);
+                        }
+                    }
                 }
             }
         }
     }
 
-    let bril_ir = match program::read_json(file_name) {
+    let mut bril_ir = match program::read_json(file_name) {
         Ok(p) => p,
         Err(e) => {
             eprintln!("{}", e);
@@ -74,6 +177,86 @@ fn main() {
         }
     };
 
-    let mut interpreter = Interpreter::new(&bril_ir, jit, jit_n, osr, osr_n);
-    interpreter.eval_program(cli_args);
+    // the pipeline the JIT compiles with, or that -opt applies up front; no
+    // passes unless -O or --passes= asks for them
+    let pipeline = match pipeline.unwrap_or_else(|| PassManager::preset(0)) {
+        Ok(mut p) => {
+            if verify {
+                p.enable_verify();
+            }
+            Arc::new(p)
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    if validate {
+        let ok = validate::run(file_name, &bril_ir, &pipeline);
+        process::exit(if ok { 0 } else { 1 });
+    } else if compile {
+        let output = match output {
+            Some(output) => output,
+            None => {
+                eprintln!("Expected -o for compile");
+                process::exit(1);
+            }
+        };
+        if let Err(e) = compiler::compile(bril_ir, &pipeline, output, !object_only) {
+            eprintln!("Couldn't compile to {}: {}", output, e);
+            process::exit(1);
+        }
+    } else if emit_opt {
+        if let Err(e) = pipeline.run_program(&mut bril_ir) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        println!("{}", serde_json::to_string_pretty(&bril_ir).unwrap());
+    } else {
+        if jit || osr {
+            if let Err(e) = pipeline.run_program_passes(&mut bril_ir) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        let bril_ir = Arc::new(bril_ir);
+        let mut interpreter = Interpreter::new(&bril_ir, jit, jit_n, osr, osr_n, pipeline.clone());
+        if let Some(capacity) = memo {
+            interpreter.enable_memo(capacity);
+        }
+        if let Some(opt_n) = opt_n {
+            interpreter.enable_tiers(opt_n);
+        }
+        if let Some(bytes) = code_limit {
+            interpreter.limit_code(bytes);
+        }
+        if let Some(dir) = jit_cache {
+            if let Err(e) = interpreter.enable_disk_cache(dir) {
+                eprintln!("Couldn't open the JIT cache in {}: {}", dir, e);
+                process::exit(1);
+            }
+        }
+        if background {
+            interpreter.enable_background();
+        }
+        let result = interpreter.eval_program(cli_args);
+        if code_limit.is_some() {
+            interpreter.code_stats().report();
+        }
+        if print_ret {
+            match result {
+                Some(val) => println!("ret {}", val),
+                None => println!("ret"),
+            }
+        }
+    }
+    if time_passes {
+        pipeline.report();
+    }
+    if let Some(path) = remarks {
+        if let Err(e) = opt::remarks::write(path) {
+            eprintln!("Couldn't write remarks to {}: {}", path, e);
+        }
+    }
 }
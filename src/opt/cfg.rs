@@ -0,0 +1,332 @@
+use super::*;
+
+use std::collections::HashMap;
+
+pub struct BasicBlock {
+    pub label: Option<String>,
+    pub instrs: Vec<Instruction>,
+}
+
+impl BasicBlock {
+    pub fn push_before_terminator(&mut self, instrs: Vec<Instruction>) {
+        let at = match self.instrs.last() {
+            Some(last) if is_terminator(last) => self.instrs.len() - 1,
+            _ => self.instrs.len(),
+        };
+        self.instrs.splice(at..at, instrs);
+    }
+}
+
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub succs: Vec<Vec<usize>>,
+    pub preds: Vec<Vec<usize>>,
+}
+
+impl Cfg {
+    pub fn new(func: &Function) -> Cfg {
+        let mut blocks = Vec::new();
+        let mut block = BasicBlock {
+            label: None,
+            instrs: Vec::new(),
+        };
+        for instr in &func.instrs {
+            if is_label(instr) {
+                if block.label.is_some() || !block.instrs.is_empty() {
+                    blocks.push(block);
+                }
+                block = BasicBlock {
+                    label: instr.label.clone(),
+                    instrs: Vec::new(),
+                };
+            } else {
+                block.instrs.push(instr.clone());
+                if is_terminator(instr) {
+                    blocks.push(block);
+                    block = BasicBlock {
+                        label: None,
+                        instrs: Vec::new(),
+                    };
+                }
+            }
+        }
+        if block.label.is_some() || !block.instrs.is_empty() {
+            blocks.push(block);
+        }
+        Cfg::from_blocks(blocks)
+    }
+
+    pub fn from_blocks(blocks: Vec<BasicBlock>) -> Cfg {
+        let mut label_map = HashMap::<&str, usize>::new();
+        for (i, block) in blocks.iter().enumerate() {
+            if let Some(label) = &block.label {
+                label_map.insert(label, i);
+            }
+        }
+
+        let mut succs = vec![Vec::new(); blocks.len()];
+        let mut preds = vec![Vec::new(); blocks.len()];
+        for (i, block) in blocks.iter().enumerate() {
+            let mut block_succs = Vec::new();
+            match block.instrs.last() {
+                Some(last) if is_terminator(last) => {
+                    for target in targets(last) {
+                        if let Some(&j) = label_map.get::<str>(target) {
+                            if !block_succs.contains(&j) {
+                                block_succs.push(j);
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    if i + 1 < blocks.len() {
+                        block_succs.push(i + 1);
+                    }
+                }
+            }
+            for &j in &block_succs {
+                preds[j].push(i);
+            }
+            succs[i] = block_succs;
+        }
+
+        Cfg {
+            blocks,
+            succs,
+            preds,
+        }
+    }
+
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut stack = Vec::new();
+        if !self.blocks.is_empty() {
+            stack.push(0);
+        }
+        while let Some(b) = stack.pop() {
+            if seen[b] {
+                continue;
+            }
+            seen[b] = true;
+            for &s in &self.succs[b] {
+                stack.push(s);
+            }
+        }
+        seen
+    }
+
+    pub fn flatten(self) -> Vec<Instruction> {
+        flatten(self.blocks)
+    }
+}
+
+// Falls-through are preserved as long as the block order is, so passes that
+// reorder blocks need to add explicit jumps first.
+pub fn flatten(blocks: Vec<BasicBlock>) -> Vec<Instruction> {
+    let mut instrs = Vec::new();
+    for block in blocks {
+        if let Some(name) = &block.label {
+            instrs.push(label(name));
+        }
+        instrs.extend(block.instrs);
+    }
+    instrs
+}
+
+// Index in the flattened instruction list of instruction `i` of block `b`.
+pub fn position(blocks: &[BasicBlock], b: usize, i: usize) -> usize {
+    let size = |block: &BasicBlock| block.label.is_some() as usize + block.instrs.len();
+    blocks[..b].iter().map(size).sum::<usize>() + blocks[b].label.is_some() as usize + i
+}
+
+// Gives every block a label and replaces falling through with an explicit
+// jump, and falling off the end with a `ret`, so blocks can be copied or
+// moved around.
+pub fn make_explicit(blocks: &mut [BasicBlock], fresh: &mut Fresh) {
+    for block in blocks.iter_mut() {
+        if block.label.is_none() {
+            block.label = Some(fresh.name("b"));
+        }
+    }
+    for i in 1..blocks.len() {
+        if !blocks[i - 1].instrs.last().is_some_and(is_terminator) {
+            let next = jmp(blocks[i].label.as_ref().unwrap());
+            blocks[i - 1].instrs.push(next);
+        }
+    }
+    if let Some(last) = blocks.last_mut() {
+        if !last.instrs.last().is_some_and(is_terminator) {
+            last.instrs.push(Instruction {
+                op: Some(OpCode::Ret),
+                args: Some(Vec::new()),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+impl Cfg {
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut seen = vec![false; self.blocks.len()];
+        let mut stack = Vec::new();
+        if !self.blocks.is_empty() {
+            seen[0] = true;
+            stack.push((0, 0));
+        }
+        while let Some((b, i)) = stack.pop() {
+            if i < self.succs[b].len() {
+                stack.push((b, i + 1));
+                let s = self.succs[b][i];
+                if !seen[s] {
+                    seen[s] = true;
+                    stack.push((s, 0));
+                }
+            } else {
+                order.push(b);
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // Immediate dominators (Cooper, Harvey and Kennedy). The entry block and
+    // unreachable blocks have none.
+    pub fn dominators(&self) -> Vec<Option<usize>> {
+        let rpo = self.reverse_postorder();
+        let mut rpo_index = vec![usize::MAX; self.blocks.len()];
+        for (i, &b) in rpo.iter().enumerate() {
+            rpo_index[b] = i;
+        }
+
+        let mut idom = vec![usize::MAX; self.blocks.len()];
+        if !rpo.is_empty() {
+            idom[rpo[0]] = rpo[0];
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in rpo.iter().skip(1) {
+                let mut new_idom = usize::MAX;
+                for &p in &self.preds[b] {
+                    if idom[p] == usize::MAX {
+                        continue;
+                    }
+                    if new_idom == usize::MAX {
+                        new_idom = p;
+                        continue;
+                    }
+                    let (mut x, mut y) = (p, new_idom);
+                    while x != y {
+                        while rpo_index[x] > rpo_index[y] {
+                            x = idom[x];
+                        }
+                        while rpo_index[y] > rpo_index[x] {
+                            y = idom[y];
+                        }
+                    }
+                    new_idom = x;
+                }
+                if idom[b] != new_idom {
+                    idom[b] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        idom.iter()
+            .enumerate()
+            .map(|(b, &d)| if d == usize::MAX || d == b { None } else { Some(d) })
+            .collect()
+    }
+
+    pub fn dominance_frontiers(&self, idom: &[Option<usize>]) -> Vec<Vec<usize>> {
+        let reachable = self.reachable();
+        let mut frontiers = vec![Vec::new(); self.blocks.len()];
+        for b in 0..self.blocks.len() {
+            if !reachable[b] || self.preds[b].len() < 2 {
+                continue;
+            }
+            for &p in &self.preds[b] {
+                if !reachable[p] {
+                    continue;
+                }
+                let mut runner = Some(p);
+                while let Some(r) = runner {
+                    if Some(r) == idom[b] {
+                        break;
+                    }
+                    if !frontiers[r].contains(&b) {
+                        frontiers[r].push(b);
+                    }
+                    runner = idom[r];
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+pub fn dominator_tree(idom: &[Option<usize>]) -> Vec<Vec<usize>> {
+    let mut children = vec![Vec::new(); idom.len()];
+    for (b, d) in idom.iter().enumerate() {
+        if let Some(d) = d {
+            children[*d].push(b);
+        }
+    }
+    children
+}
+
+pub fn dominates(idom: &[Option<usize>], a: usize, b: usize) -> bool {
+    let mut runner = Some(b);
+    while let Some(r) = runner {
+        if r == a {
+            return true;
+        }
+        runner = idom[r];
+    }
+    false
+}
+
+pub struct Loop {
+    pub header: usize,
+    pub body: Vec<usize>,
+}
+
+impl Cfg {
+    // Back edges to the same header are merged into a single loop.
+    pub fn natural_loops(&self) -> Vec<Loop> {
+        let idom = self.dominators();
+        let reachable = self.reachable();
+        let mut loops: Vec<Loop> = Vec::new();
+        for n in 0..self.blocks.len() {
+            for &h in &self.succs[n] {
+                if !reachable[n] || !dominates(&idom, h, n) {
+                    continue;
+                }
+                let mut body = vec![h];
+                let mut stack = vec![n];
+                while let Some(b) = stack.pop() {
+                    if !body.contains(&b) && reachable[b] {
+                        body.push(b);
+                        stack.extend(&self.preds[b]);
+                    }
+                }
+                match loops.iter_mut().find(|l| l.header == h) {
+                    Some(l) => {
+                        for b in body {
+                            if !l.body.contains(&b) {
+                                l.body.push(b);
+                            }
+                        }
+                    }
+                    None => loops.push(Loop { header: h, body }),
+                }
+            }
+        }
+        for l in &mut loops {
+            l.body.sort();
+        }
+        loops
+    }
+}
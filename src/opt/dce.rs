@@ -0,0 +1,100 @@
+use super::cfg::Cfg;
+use super::*;
+
+use std::collections::HashSet;
+
+pub fn run(func: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        let mut progress = remove_unreachable_blocks(func);
+        progress |= remove_dead_instrs(func);
+        progress |= remove_unused_labels(func);
+        if !progress {
+            break;
+        }
+        changed = true;
+    }
+    changed
+}
+
+// Drops functions that can't be reached through calls from main.
+pub fn remove_dead_functions(program: &mut Program) -> bool {
+    let mut live = HashSet::<String>::new();
+    let mut worklist = vec!["main".to_string()];
+    while let Some(name) = worklist.pop() {
+        if !live.insert(name.clone()) {
+            continue;
+        }
+        if let Some(func) = program.functions.iter().find(|f| f.name == name) {
+            for instr in &func.instrs {
+                if let (Some(OpCode::Call), Some(args)) = (&instr.op, &instr.args) {
+                    worklist.push(args[0].clone());
+                }
+            }
+        }
+    }
+    let before = program.functions.len();
+    program.functions.retain(|f| live.contains(&f.name));
+    program.functions.len() != before
+}
+
+fn remove_unreachable_blocks(func: &mut Function) -> bool {
+    let mut cfg = Cfg::new(func);
+    let reachable = cfg.reachable();
+    if reachable.iter().all(|&r| r) {
+        return false;
+    }
+    let mut i = 0;
+    cfg.blocks.retain(|_| {
+        i += 1;
+        reachable[i - 1]
+    });
+    func.instrs = cfg.flatten();
+    true
+}
+
+fn remove_dead_instrs(func: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        let used: HashSet<&str> = func
+            .instrs
+            .iter()
+            .flat_map(uses)
+            .map(|arg| arg.as_str())
+            .collect();
+        let dead: Vec<bool> = func
+            .instrs
+            .iter()
+            .map(|instr| match (&instr.op, &instr.dest) {
+                (Some(OpCode::Nop), _) => true,
+                (Some(_), Some(dest)) => is_pure(instr) && !used.contains(dest.as_str()),
+                _ => false,
+            })
+            .collect();
+        if !dead.contains(&true) {
+            break;
+        }
+        let mut i = 0;
+        func.instrs.retain(|_| {
+            i += 1;
+            !dead[i - 1]
+        });
+        changed = true;
+    }
+    changed
+}
+
+pub fn remove_unused_labels(func: &mut Function) -> bool {
+    let jumped_to: HashSet<String> = func
+        .instrs
+        .iter()
+        .flat_map(targets)
+        .cloned()
+        .collect();
+    let len = func.instrs.len();
+    func.instrs.retain(|instr| match &instr.label {
+        Some(label) if is_label(instr) => jumped_to.contains(label),
+        _ => true,
+    });
+    func.instrs.len() != len
+}
@@ -0,0 +1,170 @@
+use super::remarks;
+use super::*;
+
+use std::collections::HashMap;
+use std::mem;
+
+pub struct InlineConfig {
+    // instructions a single caller may grow by
+    pub budget: usize,
+    // largest callee that is considered at all
+    pub max_size: usize,
+    // rounds of inlining, which bounds how far recursive calls get unrolled
+    pub max_depth: usize,
+}
+
+impl Default for InlineConfig {
+    fn default() -> InlineConfig {
+        InlineConfig {
+            budget: 1000,
+            max_size: 100,
+            max_depth: 3,
+        }
+    }
+}
+
+fn size(func: &Function) -> usize {
+    func.instrs.iter().filter(|instr| !is_label(instr)).count()
+}
+
+pub fn run(
+    func: &mut Function,
+    program: &Program,
+    is_hot: &dyn Fn(&str) -> bool,
+    config: &InlineConfig,
+) -> bool {
+    let functions: HashMap<&str, &Function> =
+        program.functions.iter().map(|f| (f.name.as_str(), f)).collect();
+    let mut fresh = Fresh::new(func);
+    let mut budget = config.budget;
+    let mut changed = false;
+
+    // only the last round's misses are final
+    let mut missed = Vec::new();
+    for _ in 0..config.max_depth {
+        let mut progress = false;
+        missed.clear();
+        let mut instrs = Vec::new();
+        let tail: Vec<bool> = (0..func.instrs.len()).map(|i| func.tail_call(i).is_some()).collect();
+        let mut skip_ret = false;
+        for (i, instr) in mem::take(&mut func.instrs).into_iter().enumerate() {
+            if mem::replace(&mut skip_ret, false) {
+                continue;
+            }
+            let callee = match (&instr.op, &instr.args) {
+                (Some(OpCode::Call), Some(args)) => functions.get(args[0].as_str()),
+                _ => None,
+            };
+            if let Some(callee) = callee {
+                let arity = callee.args.as_ref().map_or(0, |a| a.len());
+                let cost = size(callee);
+                let declined = if cost > config.max_size {
+                    Some(format!("{} is too large ({} instructions)", callee.name, cost))
+                } else if cost > budget {
+                    Some(format!("{} exceeds the remaining budget of {}", callee.name, budget))
+                } else if arity + 1 != instr.args.as_ref().unwrap().len() {
+                    Some(format!("call to {} has the wrong number of arguments", callee.name))
+                } else if !is_hot(&callee.name) {
+                    Some(format!("{} is not hot", callee.name))
+                } else {
+                    None
+                };
+                if let Some(reason) = declined {
+                    missed.push((i, reason));
+                } else {
+                    remarks::applied("inline", &func.name, Some(i), &|| {
+                        format!("inlined {} ({} instructions)", callee.name, cost)
+                    });
+                    budget -= cost;
+                    instrs.extend(splice(&instr, callee, tail[i], &mut fresh));
+                    // the spliced body returns on its own
+                    skip_ret = tail[i];
+                    progress = true;
+                    continue;
+                }
+            }
+            instrs.push(instr);
+        }
+        func.instrs = instrs;
+        if !progress {
+            break;
+        }
+        changed = true;
+    }
+    for (i, reason) in missed {
+        remarks::missed("inline", &func.name, Some(i), &|| reason.clone());
+    }
+    changed
+}
+
+// The callee body gets its own copy of every variable and label, and each
+// `ret` becomes a jump past the end of the body. A call in tail position keeps
+// the callee's returns, so tail calls inside the callee stay tail calls.
+fn splice(call: &Instruction, callee: &Function, tail: bool, fresh: &mut Fresh) -> Vec<Instruction> {
+    let mut vars = HashMap::<String, String>::new();
+    let mut labels = HashMap::<String, String>::new();
+    let rename = |names: &mut HashMap<String, String>, fresh: &mut Fresh, name: &str| {
+        names
+            .entry(name.to_string())
+            .or_insert_with(|| fresh.name(&format!("{}.{}", callee.name, name)))
+            .clone()
+    };
+
+    let mut instrs = Vec::new();
+    let call_args = &call.args.as_ref().unwrap()[1..];
+    if let Some(params) = &callee.args {
+        for (param, arg) in params.iter().zip(call_args) {
+            let name = rename(&mut vars, fresh, &param.name);
+            instrs.push(id(&name, arg, param.r#type.clone()));
+        }
+    }
+
+    let exit = if tail {
+        None
+    } else {
+        Some(fresh.name(&format!("{}.ret", callee.name)))
+    };
+    for instr in &callee.instrs {
+        let mut instr = instr.clone();
+        if is_label(&instr) {
+            let name = rename(&mut labels, fresh, instr.label.as_ref().unwrap());
+            instrs.push(label(&name));
+            continue;
+        }
+        for arg in uses_mut(&mut instr) {
+            *arg = rename(&mut vars, fresh, arg);
+        }
+        if let Some(dest) = &mut instr.dest {
+            *dest = rename(&mut vars, fresh, dest);
+        }
+        let skip = match instr.op {
+            Some(OpCode::Jmp) => 0,
+            Some(OpCode::Br) => 1,
+            _ => usize::MAX,
+        };
+        if let Some(args) = &mut instr.args {
+            for arg in args.iter_mut().skip(skip) {
+                *arg = rename(&mut labels, fresh, arg);
+            }
+        }
+        match &exit {
+            Some(exit) if instr.op == Some(OpCode::Ret) => {
+                if let (Some(dest), Some(value)) = (&call.dest, instr.args.as_ref().and_then(|a| a.first())) {
+                    instrs.push(id(dest, value, call.r#type.clone()));
+                }
+                instrs.push(jmp(exit));
+            }
+            _ => instrs.push(instr),
+        }
+    }
+    match exit {
+        Some(exit) => instrs.push(label(&exit)),
+        // falling off the end of the callee returns from the caller too
+        None => instrs.push(Instruction {
+            op: Some(OpCode::Ret),
+            args: Some(Vec::new()),
+            ..Default::default()
+        }),
+    }
+    instrs
+}
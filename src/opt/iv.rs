@@ -0,0 +1,520 @@
+use super::cfg::{self, BasicBlock, Cfg, Loop};
+use super::licm::preheader;
+use super::ssa::{self, is_phi};
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+
+// i = phi(init, next) in the header, with next = i + step or i - step
+struct BasicIv {
+    phi: String,
+    next: String,
+    init: String,
+    step: String,
+    op: String,
+    latch: String,
+}
+
+// j = x * k, where x is a basic induction variable plus or minus an
+// invariant `offset`, taken either before or after the increment
+struct Derived {
+    at: (usize, usize),
+    iv: usize,
+    after_step: bool,
+    offset: Option<Instruction>,
+    k: String,
+}
+
+pub fn run(func: &mut Function) -> bool {
+    let mut ssa_func = func.clone();
+    ssa::to_ssa(&mut ssa_func);
+    let mut fresh = Fresh::new(&ssa_func);
+
+    let mut changed = false;
+    let mut visited = HashSet::<String>::new();
+    loop {
+        let cfg = Cfg::new(&ssa_func);
+        let next = cfg
+            .natural_loops()
+            .into_iter()
+            .filter(|l| !visited.contains(cfg.blocks[l.header].label.as_ref().unwrap()))
+            .min_by_key(|l| l.body.len());
+        let lp = match next {
+            Some(lp) => lp,
+            None => break,
+        };
+        let header = cfg.blocks[lp.header].label.clone().unwrap();
+        visited.insert(header.clone());
+        if let Some(blocks) = reduce(cfg, &lp, &header, &mut fresh) {
+            ssa_func.instrs = cfg::flatten(blocks);
+            changed = true;
+        }
+    }
+    changed |= use_shifts(&mut ssa_func, &mut fresh);
+    if !changed {
+        return false;
+    }
+
+    ssa::from_ssa(&mut ssa_func);
+    *func = ssa_func;
+    true
+}
+
+fn consts(blocks: &[BasicBlock]) -> HashMap<String, i64> {
+    blocks
+        .iter()
+        .flat_map(|b| b.instrs.iter())
+        .filter(|instr| instr.op == Some(OpCode::Const))
+        .filter_map(|instr| Some((instr.dest.clone()?, instr.value?)))
+        .collect()
+}
+
+fn binop(op: &str, dest: &str, a: &str, b: &str) -> Instruction {
+    Instruction {
+        op: Some(OpCode::BinOp(op.to_string())),
+        dest: Some(dest.to_string()),
+        args: Some(vec![a.to_string(), b.to_string()]),
+        r#type: Some("int".to_string()),
+        ..Default::default()
+    }
+}
+
+fn constant(dest: &str, value: i64) -> Instruction {
+    Instruction {
+        op: Some(OpCode::Const),
+        dest: Some(dest.to_string()),
+        value: Some(value),
+        r#type: Some("int".to_string()),
+        ..Default::default()
+    }
+}
+
+fn reduce(cfg: Cfg, lp: &Loop, header_label: &str, fresh: &mut Fresh) -> Option<Vec<BasicBlock>> {
+    let Cfg {
+        mut blocks,
+        succs,
+        preds,
+    } = cfg;
+    let pre = preheader(&mut blocks, &succs, &preds, lp, fresh);
+    let cfg = Cfg::from_blocks(blocks);
+    let lp = cfg
+        .natural_loops()
+        .into_iter()
+        .find(|l| cfg.blocks[l.header].label.as_deref() == Some(header_label))?;
+    let Cfg { mut blocks, .. } = cfg;
+    let pre_label = blocks[pre].label.clone()?;
+    let header = lp.header;
+
+    let mut defs = HashMap::<String, (usize, usize)>::new();
+    for &b in &lp.body {
+        for (i, instr) in blocks[b].instrs.iter().enumerate() {
+            if let Some(dest) = &instr.dest {
+                defs.insert(dest.clone(), (b, i));
+            }
+        }
+    }
+    let invariant = |var: &str| !defs.contains_key(var);
+    let binop_args = |instr: &Instruction| -> Option<(String, String, String)> {
+        match (&instr.op, &instr.args) {
+            (Some(OpCode::BinOp(op)), Some(args)) => Some((op.clone(), args[0].clone(), args[1].clone())),
+            _ => None,
+        }
+    };
+
+    let mut ivs = Vec::new();
+    for phi in blocks[header].instrs.iter().filter(|instr| is_phi(instr)) {
+        let (args, labels) = match (&phi.args, &phi.labels) {
+            (Some(args), Some(labels)) if args.len() == 2 => (args, labels),
+            _ => continue,
+        };
+        let outer = match labels.iter().position(|l| *l == pre_label) {
+            Some(k) => k,
+            None => continue,
+        };
+        let (init, next) = (&args[outer], &args[1 - outer]);
+        let phi_dest = phi.dest.clone().unwrap();
+        let step = match defs.get(next).and_then(|&(b, i)| binop_args(&blocks[b].instrs[i])) {
+            Some((op, a, b)) if op == "add" && a == phi_dest && invariant(&b) => (op, b),
+            Some((op, a, b)) if op == "add" && b == phi_dest && invariant(&a) => (op, a),
+            Some((op, a, b)) if op == "sub" && a == phi_dest && invariant(&b) => (op, b),
+            _ => continue,
+        };
+        ivs.push(BasicIv {
+            phi: phi_dest,
+            next: next.clone(),
+            init: init.clone(),
+            step: step.1,
+            op: step.0,
+            latch: labels[1 - outer].clone(),
+        });
+    }
+    if ivs.is_empty() {
+        return None;
+    }
+
+    let root = |var: &str| -> Option<(usize, bool)> {
+        ivs.iter().enumerate().find_map(|(k, iv)| {
+            if iv.phi == var {
+                Some((k, false))
+            } else if iv.next == var {
+                Some((k, true))
+            } else {
+                None
+            }
+        })
+    };
+    let mut derived = Vec::new();
+    for &b in &lp.body {
+        for (i, instr) in blocks[b].instrs.iter().enumerate() {
+            let (x, k) = match binop_args(instr) {
+                Some((op, x, k)) if op == "mul" && invariant(&k) => (x, k),
+                Some((op, k, x)) if op == "mul" && invariant(&k) => (x, k),
+                _ => continue,
+            };
+            if let Some((iv, after_step)) = root(&x) {
+                derived.push(Derived {
+                    at: (b, i),
+                    iv,
+                    after_step,
+                    offset: None,
+                    k,
+                });
+                continue;
+            }
+            let offset = match defs.get(&x) {
+                Some(&(ob, oi)) => &blocks[ob].instrs[oi],
+                None => continue,
+            };
+            let base = match binop_args(offset) {
+                Some((op, a, c)) if (op == "add" || op == "sub") && invariant(&c) => a,
+                Some((op, c, a)) if op == "add" && invariant(&c) => a,
+                _ => continue,
+            };
+            if let Some((iv, after_step)) = root(&base) {
+                derived.push(Derived {
+                    at: (b, i),
+                    iv,
+                    after_step,
+                    offset: Some(offset.clone()),
+                    k,
+                });
+            }
+        }
+    }
+    if derived.is_empty() {
+        return None;
+    }
+
+    let consts = consts(&blocks);
+    let mut pre_instrs = Vec::new();
+    let mut header_phis = Vec::new();
+    let mut after = HashMap::<(usize, usize), Vec<Instruction>>::new();
+    let mut replace = HashMap::<(usize, usize), Instruction>::new();
+
+    // Each product gets its own induction variable s = x * k, stepped by
+    // step * k next to the original increment.
+    let mut scaled = HashMap::<usize, (String, String, i64)>::new();
+    for d in &derived {
+        let iv = &ivs[d.iv];
+        let mut x0 = iv.init.clone();
+        if let Some(offset) = &d.offset {
+            let mut start = offset.clone();
+            for arg in uses_mut(&mut start) {
+                if *arg == iv.phi || *arg == iv.next {
+                    *arg = iv.init.clone();
+                }
+            }
+            x0 = fresh.name("iv.start");
+            start.dest = Some(x0.clone());
+            pre_instrs.push(start);
+        }
+        let s0 = fresh.name("iv.init");
+        let sk = fresh.name("iv.step");
+        let s = fresh.name("iv");
+        let s_next = fresh.name("iv.next");
+        pre_instrs.push(binop("mul", &s0, &x0, &d.k));
+        pre_instrs.push(binop("mul", &sk, &iv.step, &d.k));
+        header_phis.push(Instruction {
+            op: Some(OpCode::Phi),
+            dest: Some(s.clone()),
+            args: Some(vec![s0, s_next.clone()]),
+            labels: Some(vec![pre_label.clone(), iv.latch.clone()]),
+            r#type: Some("int".to_string()),
+            ..Default::default()
+        });
+        after
+            .entry(defs[&iv.next])
+            .or_default()
+            .push(binop(&iv.op, &s_next, &s, &sk));
+
+        let dest = blocks[d.at.0].instrs[d.at.1].dest.clone().unwrap();
+        let value = if d.after_step { &s_next } else { &s };
+        replace.insert(d.at, id(&dest, value, Some("int".to_string())));
+
+        match consts.get(&d.k) {
+            Some(&k) if k > 0 && d.offset.is_none() => {
+                scaled.entry(d.iv).or_insert((s, s_next, k));
+            }
+            _ => {}
+        }
+    }
+
+    // The exit test on a basic induction variable can compare a scaled copy
+    // instead, which may leave the original with no other uses. That's only
+    // sound if no value the test sees overflows once scaled, so the start,
+    // step and bound all have to be constants, and the test has to run on
+    // every iteration, in the header or the latch.
+    for &b in &lp.body {
+        for (i, instr) in blocks[b].instrs.iter().enumerate() {
+            let (op, a, c) = match binop_args(instr) {
+                Some(parts) => parts,
+                None => continue,
+            };
+            if !["lt", "le", "gt", "ge", "eq"].contains(&op.as_str()) || replace.contains_key(&(b, i)) {
+                continue;
+            }
+            let (var, bound, iv_first) = if invariant(&c) {
+                (a, c, true)
+            } else if invariant(&a) {
+                (c, a, false)
+            } else {
+                continue;
+            };
+            let (iv, after_step) = match root(&var) {
+                Some(r) => r,
+                None => continue,
+            };
+            let (s, s_next, k) = match scaled.get(&iv) {
+                Some(s) => s,
+                None => continue,
+            };
+            if b != header && blocks[b].label.as_ref() != Some(&ivs[iv].latch) {
+                continue;
+            }
+            let cont = match exit_branch(&blocks, &lp, b, instr.dest.as_ref().unwrap()) {
+                Some(cont) => cont,
+                None => continue,
+            };
+            let (init, step, bound_value) = match (
+                consts.get(&ivs[iv].init),
+                consts.get(&ivs[iv].step),
+                consts.get(&bound),
+            ) {
+                (Some(&init), Some(&step), Some(&bound)) => (init, step, bound),
+                _ => continue,
+            };
+            let step = if ivs[iv].op == "sub" { step.checked_neg() } else { Some(step) };
+            let start = if after_step { step.and_then(|step| init.checked_add(step)) } else { Some(init) };
+            let (lo, hi) = match (start, step) {
+                (Some(start), Some(step)) if step != 0 => {
+                    match exit_range(&op, iv_first, cont, start, step, bound_value) {
+                        Some(range) => range,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let fits = |v: i64| v.checked_mul(*k).is_some();
+            if !fits(lo.min(bound_value)) || !fits(hi.max(bound_value)) {
+                continue;
+            }
+            let bound_k = fresh.name("iv.bound");
+            pre_instrs.push(constant(&bound_k, bound_value * k));
+            let value = if after_step { s_next } else { s };
+            let mut test = instr.clone();
+            test.args = Some(if iv_first {
+                vec![value.clone(), bound_k]
+            } else {
+                vec![bound_k, value.clone()]
+            });
+            replace.insert((b, i), test);
+        }
+    }
+
+    for (b, block) in blocks.iter_mut().enumerate() {
+        let mut instrs = Vec::new();
+        if b == header {
+            instrs.append(&mut header_phis);
+        }
+        for (i, instr) in block.instrs.drain(..).enumerate() {
+            instrs.push(replace.remove(&(b, i)).unwrap_or(instr));
+            if let Some(mut extra) = after.remove(&(b, i)) {
+                instrs.append(&mut extra);
+            }
+        }
+        block.instrs = instrs;
+    }
+    blocks[pre].push_before_terminator(pre_instrs);
+
+    // a basic induction variable that only feeds its own increment is dead
+    let use_count = remove_dead(&mut blocks);
+    for iv in &ivs {
+        if use_count.get(&iv.phi) == Some(&1) && use_count.get(&iv.next) == Some(&1) {
+            for block in &mut blocks {
+                block
+                    .instrs
+                    .retain(|instr| instr.dest.as_ref() != Some(&iv.phi) && instr.dest.as_ref() != Some(&iv.next));
+            }
+        }
+    }
+    Some(blocks)
+}
+
+// Whether the loop keeps going when `cond`, tested by the branch ending block
+// `b`, is true, provided exactly one side of the branch leaves the loop.
+fn exit_branch(blocks: &[BasicBlock], lp: &Loop, b: usize, cond: &str) -> Option<bool> {
+    let br = blocks[b].instrs.last()?;
+    let args = match (&br.op, &br.args) {
+        (Some(OpCode::Br), Some(args)) if args[0] == cond => args,
+        _ => return None,
+    };
+    let in_loop = |label: &str| {
+        blocks
+            .iter()
+            .position(|block| block.label.as_deref() == Some(label))
+            .is_some_and(|k| lp.body.contains(&k))
+    };
+    match (in_loop(&args[1]), in_loop(&args[2])) {
+        (true, false) => Some(true),
+        (false, true) => Some(false),
+        _ => None,
+    }
+}
+
+// The smallest and largest values `v` an exit test `v op bound` sees while
+// the loop keeps going as long as it's `cont`, with v starting at `start`
+// and moving by `step`, or None if v might never leave.
+fn exit_range(op: &str, v_first: bool, cont: bool, start: i64, step: i64, bound: i64) -> Option<(i64, i64)> {
+    let keeps_going = |v: i64| {
+        let (a, b) = if v_first { (v, bound) } else { (bound, v) };
+        let result = match op {
+            "lt" => a < b,
+            "le" => a <= b,
+            "gt" => a > b,
+            "ge" => a >= b,
+            _ => a == b,
+        };
+        result == cont
+    };
+    if !keeps_going(start) {
+        return Some((start, start));
+    }
+    let last = if op == "eq" && cont {
+        // only bound itself keeps going, and the next step leaves it
+        start.checked_add(step)?
+    } else if op == "eq" {
+        // v has to land on bound exactly
+        let distance = bound.checked_sub(start)?;
+        if distance.signum() != step.signum() || distance.checked_rem(step)? != 0 {
+            return None;
+        }
+        bound
+    } else {
+        // everything on one side of bound keeps going, and v has to head
+        // for the other, which it reaches within a step of bound
+        let upwards = match (keeps_going(i64::MIN), keeps_going(i64::MAX)) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => return None,
+        };
+        if upwards != (step > 0) {
+            return None;
+        }
+        bound.checked_add(step)?
+    };
+    Some((start.min(last), start.max(last)))
+}
+
+// Drops pure instructions whose results are never read, and returns how often
+// each remaining variable is.
+fn remove_dead(blocks: &mut [BasicBlock]) -> HashMap<String, usize> {
+    loop {
+        let mut use_count = HashMap::<String, usize>::new();
+        for instr in blocks.iter().flat_map(|b| b.instrs.iter()) {
+            for arg in uses(instr) {
+                *use_count.entry(arg.clone()).or_insert(0) += 1;
+            }
+        }
+        let mut changed = false;
+        for block in blocks.iter_mut() {
+            let len = block.instrs.len();
+            block.instrs.retain(|instr| match &instr.dest {
+                Some(dest) => !is_pure(instr) || use_count.contains_key(dest),
+                None => true,
+            });
+            changed |= block.instrs.len() != len;
+        }
+        if !changed {
+            return use_count;
+        }
+    }
+}
+
+fn power_of_two(value: Option<&i64>) -> Option<i64> {
+    match value {
+        Some(&v) if v > 1 && v & (v - 1) == 0 => Some(v.trailing_zeros() as i64),
+        _ => None,
+    }
+}
+
+// Multiplication and division by a power of two become shifts. Division
+// rounds towards zero, so negative dividends are biased by 2^k - 1 first.
+fn use_shifts(func: &mut Function, fresh: &mut Fresh) -> bool {
+    let consts: HashMap<String, i64> = func
+        .instrs
+        .iter()
+        .filter(|instr| instr.op == Some(OpCode::Const))
+        .filter_map(|instr| Some((instr.dest.clone()?, instr.value?)))
+        .collect();
+
+    let mut changed = false;
+    let mut instrs = Vec::new();
+    for instr in func.instrs.drain(..) {
+        let (op, a, b, dest) = match (&instr.op, &instr.args, &instr.dest) {
+            (Some(OpCode::BinOp(op)), Some(args), Some(dest)) => (op, &args[0], &args[1], dest),
+            _ => {
+                instrs.push(instr);
+                continue;
+            }
+        };
+        let shift = match op.as_str() {
+            "mul" => match (power_of_two(consts.get(b)), power_of_two(consts.get(a))) {
+                (Some(k), _) => Some((a, k)),
+                (None, Some(k)) => Some((b, k)),
+                _ => None,
+            },
+            "div" => power_of_two(consts.get(b)).map(|k| (a, k)),
+            _ => None,
+        };
+        let (x, k) = match shift {
+            Some(shift) => shift,
+            None => {
+                instrs.push(instr);
+                continue;
+            }
+        };
+
+        let amount = fresh.name("shift");
+        instrs.push(constant(&amount, k));
+        if op == "mul" {
+            instrs.push(binop("shl", dest, x, &amount));
+        } else {
+            let zero = fresh.name("shift.zero");
+            let negative = fresh.name("shift.neg");
+            let scaled = fresh.name("shift.scaled");
+            let bias = fresh.name("shift.bias");
+            let biased = fresh.name("shift.biased");
+            instrs.push(constant(&zero, 0));
+            let mut test = binop("lt", &negative, x, &zero);
+            test.r#type = Some("bool".to_string());
+            instrs.push(test);
+            instrs.push(binop("shl", &scaled, &negative, &amount));
+            instrs.push(binop("sub", &bias, &scaled, &negative));
+            instrs.push(binop("add", &biased, x, &bias));
+            instrs.push(binop("shr", dest, &biased, &amount));
+        }
+        changed = true;
+    }
+    func.instrs = instrs;
+    changed
+}
@@ -0,0 +1,71 @@
+use super::cfg::{self, Cfg};
+use super::*;
+
+// Profile key for the unlabeled block a function starts with.
+pub static ENTRY: &str = "";
+
+// Orders blocks so the hottest successor of each block comes right after it,
+// which lets codegen turn that edge into a fall-through. Blocks the profile
+// saw zero times go to the end; blocks it knows nothing about, e.g. ones
+// created by other passes, keep their relative order.
+pub fn run(
+    func: &mut Function,
+    count: &dyn Fn(&str) -> Option<i64>,
+    edge: &dyn Fn(&str, &str) -> i64,
+) -> bool {
+    let cfg = Cfg::new(func);
+    let names: Vec<Option<String>> = cfg
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(b, block)| match &block.label {
+            Some(label) => Some(label.clone()),
+            None if b == 0 => Some(ENTRY.to_string()),
+            None => None,
+        })
+        .collect();
+    let weight = |a: usize, b: usize| match (&names[a], &names[b]) {
+        (Some(a), Some(b)) => edge(a, b),
+        _ => 0,
+    };
+    let cold = |b: usize| b != 0 && names[b].as_ref().is_some_and(|l| count(l) == Some(0));
+
+    let n = cfg.blocks.len();
+    if !(0..n).any(|a| cfg.succs[a].iter().any(|&b| weight(a, b) > 0)) {
+        return false;
+    }
+
+    let mut order = Vec::new();
+    let mut placed = vec![false; n];
+    for &pass_cold in &[false, true] {
+        for seed in 0..n {
+            if placed[seed] || cold(seed) != pass_cold {
+                continue;
+            }
+            let mut b = seed;
+            loop {
+                placed[b] = true;
+                order.push(b);
+                let next = cfg.succs[b]
+                    .iter()
+                    .cloned()
+                    .filter(|&s| !placed[s] && weight(b, s) > 0)
+                    .max_by_key(|&s| weight(b, s));
+                match next {
+                    Some(s) => b = s,
+                    None => break,
+                }
+            }
+        }
+    }
+    if order.iter().enumerate().all(|(i, &b)| i == b) {
+        return false;
+    }
+
+    let mut fresh = Fresh::new(func);
+    let mut blocks = cfg.blocks;
+    cfg::make_explicit(&mut blocks, &mut fresh);
+    let mut blocks: Vec<Option<_>> = blocks.into_iter().map(Some).collect();
+    func.instrs = cfg::flatten(order.into_iter().map(|b| blocks[b].take().unwrap()).collect());
+    true
+}
@@ -0,0 +1,227 @@
+use super::cfg::{self, BasicBlock, Cfg, Loop};
+use super::remarks;
+use super::ssa::{self, is_phi};
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+
+pub fn run(func: &mut Function) -> bool {
+    let mut ssa_func = func.clone();
+    ssa::to_ssa(&mut ssa_func);
+    let mut fresh = Fresh::new(&ssa_func);
+
+    let consts: HashMap<String, i64> = ssa_func
+        .instrs
+        .iter()
+        .filter(|instr| instr.op == Some(OpCode::Const))
+        .filter_map(|instr| Some((instr.dest.clone()?, instr.value?)))
+        .collect();
+
+    // Innermost loops go first, so code hoisted into their preheaders can
+    // keep moving out of the enclosing loop.
+    let mut changed = false;
+    let mut visited = HashSet::<String>::new();
+    loop {
+        let cfg = Cfg::new(&ssa_func);
+        let next = cfg
+            .natural_loops()
+            .into_iter()
+            .filter(|l| {
+                let header = cfg.blocks[l.header].label.as_ref().unwrap();
+                !visited.contains(header)
+            })
+            .min_by_key(|l| l.body.len());
+        let lp = match next {
+            Some(lp) => lp,
+            None => break,
+        };
+        visited.insert(cfg.blocks[lp.header].label.clone().unwrap());
+        if let Some(blocks) = hoist(cfg, &lp, &consts, &func.name, &mut fresh) {
+            ssa_func.instrs = cfg::flatten(blocks);
+            changed = true;
+        }
+    }
+    if !changed {
+        return false;
+    }
+
+    ssa::from_ssa(&mut ssa_func);
+    *func = ssa_func;
+    true
+}
+
+// Division can trap, so it is only moved when the divisor is a constant that
+// can't fault.
+fn can_hoist(instr: &Instruction, consts: &HashMap<String, i64>) -> bool {
+    match (&instr.op, &instr.args) {
+        (Some(OpCode::Phi), _) | (Some(OpCode::Nop), _) => false,
+        (Some(OpCode::BinOp(op)), Some(args)) if op == "div" => match consts.get(&args[1]) {
+            Some(&c) => c != 0 && c != -1,
+            None => false,
+        },
+        _ => instr.dest.is_some() && is_pure(instr),
+    }
+}
+
+fn hoist(
+    cfg: Cfg,
+    lp: &Loop,
+    consts: &HashMap<String, i64>,
+    func_name: &str,
+    fresh: &mut Fresh,
+) -> Option<Vec<BasicBlock>> {
+    let Cfg {
+        mut blocks,
+        succs,
+        preds,
+    } = cfg;
+
+    let defined: HashSet<&str> = lp
+        .body
+        .iter()
+        .flat_map(|&b| blocks[b].instrs.iter())
+        .filter_map(|instr| instr.dest.as_deref())
+        .collect();
+
+    // SSA guarantees a single definition, so an instruction is invariant
+    // once all its operands come from outside the loop or from other
+    // invariant instructions.
+    let mut invariant = HashSet::<&str>::new();
+    let mut order = Vec::new();
+    loop {
+        let mut progress = false;
+        for &b in &lp.body {
+            for (i, instr) in blocks[b].instrs.iter().enumerate() {
+                let dest = match &instr.dest {
+                    Some(dest) => dest.as_str(),
+                    None => continue,
+                };
+                if invariant.contains(dest) || !can_hoist(instr, consts) {
+                    continue;
+                }
+                if uses(instr)
+                    .iter()
+                    .all(|arg| !defined.contains(arg.as_str()) || invariant.contains(arg.as_str()))
+                {
+                    invariant.insert(dest);
+                    order.push((b, i));
+                    progress = true;
+                }
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+    if order.is_empty() {
+        return None;
+    }
+
+    let header = blocks[lp.header].label.clone().unwrap();
+    for &(b, i) in &order {
+        let dest = blocks[b].instrs[i].dest.clone().unwrap();
+        remarks::applied("licm", func_name, Some(cfg::position(&blocks, b, i)), &|| {
+            format!("hoisted {} out of the loop at {}", dest, header)
+        });
+    }
+    let moved: Vec<Instruction> = order
+        .iter()
+        .map(|&(b, i)| blocks[b].instrs[i].clone())
+        .collect();
+    let hoisted: HashSet<(usize, usize)> = order.into_iter().collect();
+    for &b in &lp.body {
+        let mut i = 0;
+        blocks[b].instrs.retain(|_| {
+            i += 1;
+            !hoisted.contains(&(b, i - 1))
+        });
+    }
+
+    let pre = preheader(&mut blocks, &succs, &preds, lp, fresh);
+    blocks[pre].push_before_terminator(moved);
+    Some(blocks)
+}
+
+// Returns the block every entry into the loop passes through right before
+// the header, creating one if needed. Block indices from the header on shift
+// by one when a block is created.
+pub fn preheader(
+    blocks: &mut Vec<BasicBlock>,
+    succs: &[Vec<usize>],
+    preds: &[Vec<usize>],
+    lp: &Loop,
+    fresh: &mut Fresh,
+) -> usize {
+    let header = lp.header;
+    let outside: Vec<usize> = preds[header]
+        .iter()
+        .cloned()
+        .filter(|p| !lp.body.contains(p))
+        .collect();
+    if outside.len() == 1 && succs[outside[0]] == [header] {
+        return outside[0];
+    }
+
+    let header_label = blocks[header].label.clone().unwrap();
+    let preheader_label = fresh.name("preheader");
+    let outside_labels: Vec<String> = outside
+        .iter()
+        .filter_map(|&p| blocks[p].label.clone())
+        .collect();
+
+    // header phis merge everything from outside the loop in the preheader
+    let mut instrs = Vec::new();
+    for phi in blocks[header].instrs.iter_mut().filter(|instr| is_phi(instr)) {
+        let (args, labels) = match (&mut phi.args, &mut phi.labels) {
+            (Some(args), Some(labels)) => (args, labels),
+            _ => continue,
+        };
+        let mut outer = Vec::new();
+        let mut k = 0;
+        while k < labels.len() {
+            if outside_labels.contains(&labels[k]) {
+                outer.push((labels.remove(k), args.remove(k)));
+            } else {
+                k += 1;
+            }
+        }
+        let arg = if outer.iter().all(|(_, a)| *a == outer[0].1) {
+            match outer.first() {
+                Some((_, a)) => a.clone(),
+                None => continue,
+            }
+        } else {
+            let dest = fresh.name(phi.dest.as_ref().unwrap());
+            let (outer_labels, outer_args): (Vec<String>, Vec<String>) = outer.into_iter().unzip();
+            instrs.push(Instruction {
+                op: Some(OpCode::Phi),
+                dest: Some(dest.clone()),
+                r#type: phi.r#type.clone(),
+                args: Some(outer_args),
+                labels: Some(outer_labels),
+                ..Default::default()
+            });
+            dest
+        };
+        args.push(arg);
+        labels.push(preheader_label.clone());
+    }
+    instrs.push(jmp(&header_label));
+
+    if header > 0 && !blocks[header - 1].instrs.last().is_some_and(is_terminator) {
+        blocks[header - 1].instrs.push(jmp(&header_label));
+    }
+    for &p in &outside {
+        if let Some(last) = blocks[p].instrs.last_mut() {
+            retarget(last, &header_label, &preheader_label);
+        }
+    }
+    blocks.insert(
+        header,
+        BasicBlock {
+            label: Some(preheader_label),
+            instrs,
+        },
+    );
+    header
+}
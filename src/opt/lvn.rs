@@ -0,0 +1,138 @@
+use super::cfg::{self, Cfg};
+use super::*;
+
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Value {
+    Const(i64),
+    Op(String, Vec<usize>),
+}
+
+// Numbers the values computed in a block and keeps, for each number, the
+// variable that first held it. A variable reassigned later in the block no
+// longer holds its number, so it stops being the one to reuse.
+struct Numbering {
+    numbers: HashMap<String, usize>,
+    values: HashMap<Value, usize>,
+    homes: Vec<String>,
+}
+
+impl Numbering {
+    fn new() -> Numbering {
+        Numbering {
+            numbers: HashMap::new(),
+            values: HashMap::new(),
+            homes: Vec::new(),
+        }
+    }
+
+    // Values live on entry to the block get a number of their own.
+    fn number(&mut self, var: &str) -> usize {
+        if let Some(&n) = self.numbers.get(var) {
+            return n;
+        }
+        self.fresh(var)
+    }
+
+    fn fresh(&mut self, var: &str) -> usize {
+        self.homes.push(var.to_string());
+        self.numbers.insert(var.to_string(), self.homes.len() - 1);
+        self.homes.len() - 1
+    }
+
+    // The variable still holding value `n`, if any.
+    fn home(&self, n: usize) -> Option<&String> {
+        let home = &self.homes[n];
+        if self.numbers.get(home) == Some(&n) {
+            Some(home)
+        } else {
+            None
+        }
+    }
+}
+
+fn commutes(op: &str) -> bool {
+    ["add", "mul", "eq", "and", "or"].contains(&op)
+}
+
+// Local value numbering: within each block, reads go to the first variable
+// that holds the same value, and recomputing a value already held becomes a
+// copy of it.
+pub fn run(func: &mut Function) -> bool {
+    let mut blocks = Cfg::new(func).blocks;
+    let mut changed = false;
+    for block in &mut blocks {
+        let mut numbering = Numbering::new();
+        for instr in &mut block.instrs {
+            if instr.op == Some(OpCode::Phi) {
+                // phi arguments are read on the way in from other blocks
+                if let Some(dest) = &instr.dest {
+                    numbering.fresh(dest);
+                }
+                continue;
+            }
+            for arg in uses_mut(instr) {
+                let n = numbering.number(arg);
+                if let Some(home) = numbering.home(n) {
+                    if home != arg {
+                        *arg = home.clone();
+                        changed = true;
+                    }
+                }
+            }
+
+            let dest = match &instr.dest {
+                Some(dest) => dest.clone(),
+                None => continue,
+            };
+            let value = match (&instr.op, &instr.args) {
+                (Some(OpCode::Const), _) => instr.value.map(Value::Const),
+                (Some(OpCode::Id), Some(args)) => {
+                    // a copy holds the same value as its source
+                    let n = numbering.number(&args[0]);
+                    numbering.numbers.insert(dest, n);
+                    continue;
+                }
+                (Some(OpCode::BinOp(op)), Some(args))
+                | (Some(OpCode::BinOpBool(op)), Some(args))
+                | (Some(OpCode::UnOpBool(op)), Some(args)) => {
+                    let mut nums: Vec<usize> = args.iter().map(|arg| numbering.number(arg)).collect();
+                    if commutes(op) {
+                        nums.sort();
+                    }
+                    Some(Value::Op(op.clone(), nums))
+                }
+                _ => None,
+            };
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    numbering.fresh(&dest);
+                    continue;
+                }
+            };
+
+            let held = numbering.values.get(&value).and_then(|&n| numbering.home(n).map(|home| (n, home.clone())));
+            match held {
+                Some((n, home)) => {
+                    // constants stay as they are, since reads of them are
+                    // redirected anyway
+                    if instr.op != Some(OpCode::Const) {
+                        *instr = id(&dest, &home, instr.r#type.clone());
+                        changed = true;
+                    }
+                    numbering.numbers.insert(dest, n);
+                }
+                None => {
+                    let n = numbering.fresh(&dest);
+                    numbering.values.insert(value, n);
+                }
+            }
+        }
+    }
+    if changed {
+        func.instrs = cfg::flatten(blocks);
+    }
+    changed
+}
@@ -0,0 +1,198 @@
+use super::{dce, inline, iv, licm, lvn, sccp, simplify, specialize, ssa, unroll, verify};
+use crate::program::*;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Everything a function pass may look at besides the function it rewrites.
+pub struct Context<'a> {
+    pub program: &'a Program,
+    pub is_hot: &'a dyn Fn(&str) -> bool,
+}
+
+#[derive(Clone, Copy)]
+pub enum Pass {
+    Program(fn(&mut Program) -> bool),
+    Function(fn(&mut Function, &Context) -> bool),
+}
+
+// Every name lookup knows, for errors to list.
+static PASSES: [&str; 11] = [
+    "dce", "dfe", "inline", "iv", "licm", "lvn", "sccp", "simplify", "spec", "ssa", "unroll",
+];
+
+pub fn lookup(name: &str) -> Option<Pass> {
+    let pass = match name {
+        "dce" => Pass::Function(|func, _| dce::run(func)),
+        "dfe" => Pass::Program(dce::remove_dead_functions),
+        "inline" => Pass::Function(|func, ctx| {
+            inline::run(func, ctx.program, ctx.is_hot, &inline::InlineConfig::default())
+        }),
+        "iv" => Pass::Function(|func, _| iv::run(func)),
+        "licm" => Pass::Function(|func, _| licm::run(func)),
+        "lvn" => Pass::Function(|func, _| lvn::run(func)),
+        "sccp" => Pass::Function(|func, _| sccp::run(func)),
+        "simplify" => Pass::Function(|func, _| simplify::run(func)),
+        "spec" => Pass::Program(specialize::run),
+        // renames every assignment apart, with copies where phis would go
+        "ssa" => Pass::Function(|func, _| {
+            ssa::to_ssa(func);
+            ssa::from_ssa(func);
+            true
+        }),
+        "unroll" => Pass::Function(|func, _| unroll::run(func, &unroll::UnrollConfig::default())),
+        _ => return None,
+    };
+    Some(pass)
+}
+
+// Shared with the JIT's compiler thread, hence the lock on the timings.
+pub struct PassManager {
+    passes: Vec<(String, Pass)>,
+    verify: bool,
+    timings: Mutex<HashMap<String, (usize, Duration)>>,
+}
+
+impl Default for PassManager {
+    fn default() -> PassManager {
+        PassManager {
+            passes: Vec::new(),
+            verify: false,
+            timings: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl PassManager {
+    // A comma-separated list of pass names, e.g. "sccp,licm,dce".
+    pub fn parse(pipeline: &str) -> Result<PassManager, String> {
+        let mut manager = PassManager::default();
+        for name in pipeline.split(',').filter(|name| !name.is_empty()) {
+            manager.add(name)?;
+        }
+        Ok(manager)
+    }
+
+    // Checks the IR after every pass, failing on the first one to break it.
+    pub fn enable_verify(&mut self) {
+        self.verify = true;
+    }
+
+    pub fn preset(level: u32) -> Result<PassManager, String> {
+        match level {
+            0 => PassManager::parse(""),
+            1 => PassManager::parse("sccp,dce,simplify"),
+            2 => PassManager::parse("spec,inline,sccp,simplify,licm,unroll,iv,dce,simplify,dfe"),
+            _ => Err(format!("Unknown optimization level {}", level)),
+        }
+    }
+
+    pub fn add(&mut self, name: &str) -> Result<(), String> {
+        match lookup(name) {
+            Some(pass) => {
+                self.passes.push((name.to_string(), pass));
+                Ok(())
+            }
+            None => Err(format!("Unknown pass {}, expected one of {}", name, PASSES.join(", "))),
+        }
+    }
+
+    // The pipeline as parse takes it.
+    pub fn names(&self) -> String {
+        self.passes.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(",")
+    }
+
+    pub fn run_program(&self, program: &mut Program) -> Result<(), String> {
+        for (name, pass) in &self.passes {
+            self.run_pass(name, *pass, program)?;
+        }
+        Ok(())
+    }
+
+    // The program after each pass in turn, for finding the pass that broke it.
+    pub fn stages(&self, program: &Program) -> Result<Vec<(String, Program)>, String> {
+        let mut program = program.clone();
+        let mut stages = Vec::new();
+        for (name, pass) in &self.passes {
+            self.run_pass(name, *pass, &mut program)?;
+            stages.push((name.clone(), program.clone()));
+        }
+        Ok(stages)
+    }
+
+    fn run_pass(&self, name: &str, pass: Pass, program: &mut Program) -> Result<(), String> {
+        let start = Instant::now();
+        match pass {
+            Pass::Program(run) => {
+                run(program);
+            }
+            Pass::Function(run) => {
+                // callees are seen as they were before this pass started
+                let snapshot = program.clone();
+                let ctx = Context {
+                    program: &snapshot,
+                    is_hot: &|_| true,
+                };
+                for func in &mut program.functions {
+                    run(func, &ctx);
+                }
+            }
+        }
+        self.record(name, start.elapsed());
+        if self.verify {
+            verify::program(program).map_err(|e| invalid(name, e))?;
+        }
+        Ok(())
+    }
+
+    // Program passes need the whole program, so code compiled a function at
+    // a time gets them from here, up front, and run_function skips them.
+    pub fn run_program_passes(&self, program: &mut Program) -> Result<(), String> {
+        for (name, pass) in &self.passes {
+            if let Pass::Program(_) = pass {
+                self.run_pass(name, *pass, program)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run_function(&self, func: &mut Function, ctx: &Context) -> Result<(), String> {
+        for (name, pass) in &self.passes {
+            if let Pass::Function(run) = pass {
+                let start = Instant::now();
+                run(func, ctx);
+                self.record(name, start.elapsed());
+                if self.verify {
+                    verify::function(func, ctx.program).map_err(|e| invalid(name, e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&self, name: &str, elapsed: Duration) {
+        let mut timings = self.timings.lock().unwrap();
+        let entry = timings.entry(name.to_string()).or_insert((0, Duration::default()));
+        entry.0 += 1;
+        entry.1 += elapsed;
+    }
+
+    pub fn report(&self) {
+        let timings = self.timings.lock().unwrap();
+        let mut seen = Vec::new();
+        eprintln!("{:<10} {:>8} {:>12}", "pass", "runs", "time (us)");
+        for (name, _) in &self.passes {
+            if seen.contains(&name) {
+                continue;
+            }
+            seen.push(name);
+            let (runs, time) = timings.get(name).cloned().unwrap_or_default();
+            eprintln!("{:<10} {:>8} {:>12}", name, runs, time.as_micros());
+        }
+    }
+}
+
+fn invalid(pass: &str, error: String) -> String {
+    format!("Invalid IR after {}: {}", pass, error)
+}
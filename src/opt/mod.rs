@@ -0,0 +1,162 @@
+use crate::program::*;
+
+use std::collections::{HashMap, HashSet};
+
+pub mod cfg;
+pub mod dce;
+pub mod inline;
+pub mod iv;
+pub mod layout;
+pub mod licm;
+pub mod lvn;
+pub mod manager;
+pub mod purity;
+pub mod regalloc;
+pub mod remarks;
+pub mod sccp;
+pub mod simplify;
+pub mod specialize;
+pub mod ssa;
+pub mod unroll;
+pub mod verify;
+
+// Variables read by an instruction. Jump targets and callee names live in
+// `args` too, so they have to be skipped.
+pub fn uses(instr: &Instruction) -> &[String] {
+    match (&instr.op, &instr.args) {
+        (Some(OpCode::Jmp), _) => &[],
+        (Some(OpCode::Br), Some(args)) => &args[..1.min(args.len())],
+        (Some(OpCode::Call), Some(args)) => &args[1.min(args.len())..],
+        (_, Some(args)) => args,
+        (_, None) => &[],
+    }
+}
+
+pub fn uses_mut(instr: &mut Instruction) -> &mut [String] {
+    match (&instr.op, &mut instr.args) {
+        (Some(OpCode::Jmp), _) => &mut [],
+        (Some(OpCode::Br), Some(args)) => {
+            let n = 1.min(args.len());
+            &mut args[..n]
+        }
+        (Some(OpCode::Call), Some(args)) => {
+            let n = 1.min(args.len());
+            &mut args[n..]
+        }
+        (_, Some(args)) => args,
+        (_, None) => &mut [],
+    }
+}
+
+// Labels an instruction may transfer control to.
+pub fn targets(instr: &Instruction) -> &[String] {
+    match (&instr.op, &instr.args) {
+        (Some(OpCode::Jmp), Some(args)) => &args[..1.min(args.len())],
+        (Some(OpCode::Br), Some(args)) => &args[1.min(args.len())..],
+        _ => &[],
+    }
+}
+
+pub fn retarget(instr: &mut Instruction, from: &str, to: &str) {
+    let skip = match instr.op {
+        Some(OpCode::Jmp) => 0,
+        Some(OpCode::Br) => 1,
+        _ => return,
+    };
+    if let Some(args) = &mut instr.args {
+        for arg in args.iter_mut().skip(skip) {
+            if arg == from {
+                *arg = to.to_string();
+            }
+        }
+    }
+}
+
+pub fn is_label(instr: &Instruction) -> bool {
+    instr.op.is_none() && instr.label.is_some()
+}
+
+pub fn is_terminator(instr: &Instruction) -> bool {
+    matches!(instr.op, Some(OpCode::Jmp) | Some(OpCode::Br) | Some(OpCode::Ret))
+}
+
+// Instructions that can be deleted or moved freely when their result is
+// unused. `div` can trap, so it only counts when the divisor is known.
+pub fn is_pure(instr: &Instruction) -> bool {
+    match &instr.op {
+        Some(OpCode::BinOp(op)) => op != "div",
+        Some(OpCode::Const)
+        | Some(OpCode::Id)
+        | Some(OpCode::BinOpBool(_))
+        | Some(OpCode::UnOpBool(_))
+        | Some(OpCode::Phi)
+        | Some(OpCode::Nop) => true,
+        _ => false,
+    }
+}
+
+pub fn label(name: &str) -> Instruction {
+    Instruction {
+        label: Some(name.to_string()),
+        ..Default::default()
+    }
+}
+
+pub fn id(dest: &str, src: &str, r#type: Option<String>) -> Instruction {
+    Instruction {
+        op: Some(OpCode::Id),
+        dest: Some(dest.to_string()),
+        args: Some(vec![src.to_string()]),
+        r#type,
+        ..Default::default()
+    }
+}
+
+pub fn jmp(target: &str) -> Instruction {
+    Instruction {
+        op: Some(OpCode::Jmp),
+        args: Some(vec![target.to_string()]),
+        ..Default::default()
+    }
+}
+
+// Generates variable and label names that don't clash with any already used
+// in a function.
+pub struct Fresh {
+    taken: HashSet<String>,
+    counters: HashMap<String, usize>,
+}
+
+impl Fresh {
+    pub fn new(func: &Function) -> Fresh {
+        let mut taken = HashSet::new();
+        if let Some(args) = &func.args {
+            for arg in args {
+                taken.insert(arg.name.clone());
+            }
+        }
+        for instr in &func.instrs {
+            if let Some(dest) = &instr.dest {
+                taken.insert(dest.clone());
+            }
+            if let Some(label) = &instr.label {
+                taken.insert(label.clone());
+            }
+        }
+        Fresh {
+            taken,
+            counters: HashMap::new(),
+        }
+    }
+
+    pub fn name(&mut self, base: &str) -> String {
+        let i = self.counters.entry(base.to_string()).or_insert(0);
+        loop {
+            let name = format!("{}.{}", base, i);
+            *i += 1;
+            if self.taken.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use super::*;
+
+use std::collections::HashSet;
+
+// Functions whose only effect is their return value: they don't print and
+// only call other pure functions. Calls to unknown functions count as
+// effects. Starts from every print-free function and drops callers of
+// impure ones until nothing changes, so recursion stays pure.
+pub fn pure_functions(program: &Program) -> HashSet<String> {
+    let mut pure: HashSet<String> = program
+        .functions
+        .iter()
+        .filter(|f| f.instrs.iter().all(|instr| instr.op != Some(OpCode::Print)))
+        .map(|f| f.name.clone())
+        .collect();
+    loop {
+        let impure: Vec<String> = program
+            .functions
+            .iter()
+            .filter(|f| pure.contains(&f.name))
+            .filter(|f| {
+                f.instrs.iter().any(|instr| match (&instr.op, &instr.args) {
+                    (Some(OpCode::Call), Some(args)) => !pure.contains(&args[0]),
+                    _ => false,
+                })
+            })
+            .map(|f| f.name.clone())
+            .collect();
+        if impure.is_empty() {
+            return pure;
+        }
+        for name in impure {
+            pure.remove(&name);
+        }
+    }
+}
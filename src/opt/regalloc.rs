@@ -0,0 +1,230 @@
+use super::cfg::{self, Cfg};
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    Reg(u8),
+    Slot(usize),
+}
+
+// Positions, as indices into the instruction list, over which a variable may
+// hold a value. Intervals have no holes, so a variable keeps one location
+// from its first definition to its last use.
+pub struct Interval {
+    pub var: String,
+    pub start: usize,
+    pub end: usize,
+    weight: f64,
+}
+
+pub struct Allocation {
+    pub intervals: Vec<Interval>,
+    pub locations: HashMap<String, Location>,
+    pub num_slots: usize,
+}
+
+impl Allocation {
+    pub fn live_at(&self, pos: usize) -> impl Iterator<Item = &Interval> {
+        self.intervals
+            .iter()
+            .filter(move |interval| interval.start <= pos && pos <= interval.end)
+    }
+
+    pub fn registers(&self) -> Vec<u8> {
+        let mut registers: Vec<u8> = self
+            .locations
+            .values()
+            .filter_map(|location| match location {
+                Location::Reg(reg) => Some(*reg),
+                Location::Slot(_) => None,
+            })
+            .collect();
+        registers.sort();
+        registers.dedup();
+        registers
+    }
+}
+
+// Instructions that end up calling into Rust, clobbering caller-saved registers.
+fn is_call(instr: &Instruction) -> bool {
+    matches!(instr.op, Some(OpCode::Call) | Some(OpCode::Print))
+}
+
+pub fn intervals(func: &Function) -> Vec<Interval> {
+    let cfg = Cfg::new(func);
+    let n = cfg.blocks.len();
+
+    let mut gen = vec![HashSet::<&str>::new(); n];
+    let mut kill = vec![HashSet::<&str>::new(); n];
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for instr in &block.instrs {
+            for arg in uses(instr) {
+                if !kill[b].contains::<str>(arg) {
+                    gen[b].insert(arg);
+                }
+            }
+            if let Some(dest) = &instr.dest {
+                kill[b].insert(dest);
+            }
+        }
+    }
+    let mut live_in = vec![HashSet::<&str>::new(); n];
+    let mut live_out = vec![HashSet::<&str>::new(); n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in (0..n).rev() {
+            let out: HashSet<&str> = cfg.succs[b].iter().flat_map(|&s| live_in[s].iter().cloned()).collect();
+            let mut live: HashSet<&str> = out.difference(&kill[b]).cloned().collect();
+            live.extend(&gen[b]);
+            if live != live_in[b] || out != live_out[b] {
+                live_in[b] = live;
+                live_out[b] = out;
+                changed = true;
+            }
+        }
+    }
+
+    // uses inside loops count for more when deciding what to spill
+    let mut depth = vec![0; n];
+    for lp in cfg.natural_loops() {
+        for &b in &lp.body {
+            depth[b] += 1;
+        }
+    }
+
+    let mut ranges = HashMap::<&str, (usize, usize, f64)>::new();
+    if let Some(params) = &func.args {
+        for param in params {
+            extend(&mut ranges, &param.name, 0, 0.0);
+        }
+    }
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let first = cfg::position(&cfg.blocks, b, 0) - block.label.is_some() as usize;
+        let last = (cfg::position(&cfg.blocks, b, block.instrs.len()) - 1).max(first);
+        for var in &live_in[b] {
+            extend(&mut ranges, var, first, 0.0);
+        }
+        for var in &live_out[b] {
+            extend(&mut ranges, var, last, 0.0);
+        }
+        let weight = 10f64.powi(depth[b].min(4));
+        for (i, instr) in block.instrs.iter().enumerate() {
+            let pos = cfg::position(&cfg.blocks, b, i);
+            for arg in uses(instr) {
+                extend(&mut ranges, arg, pos, weight);
+            }
+            if let Some(dest) = &instr.dest {
+                extend(&mut ranges, dest, pos, weight);
+            }
+        }
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(var, (start, end, weight))| Interval {
+            var: var.to_string(),
+            start,
+            end,
+            weight,
+        })
+        .collect();
+    intervals.sort_by(|a, b| (a.start, a.end, &a.var).cmp(&(b.start, b.end, &b.var)));
+    intervals
+}
+
+fn extend<'a>(ranges: &mut HashMap<&'a str, (usize, usize, f64)>, var: &'a str, pos: usize, weight: f64) {
+    let range = ranges.entry(var).or_insert((pos, pos, 0.0));
+    range.0 = range.0.min(pos);
+    range.1 = range.1.max(pos);
+    range.2 += weight;
+}
+
+// Every variable in a stack slot of its own, for code that has to be ready
+// quickly more than it has to be fast.
+pub fn spill_all(func: &Function) -> Allocation {
+    let intervals = intervals(func);
+    let locations = intervals
+        .iter()
+        .enumerate()
+        .map(|(k, interval)| (interval.var.clone(), Location::Slot(k)))
+        .collect();
+    Allocation {
+        num_slots: intervals.len(),
+        intervals,
+        locations,
+    }
+}
+
+// Linear scan over live intervals (Poletto and Sarkar). Values live across a
+// call prefer registers the callee preserves, others ones it may clobber. When
+// registers run out the interval with the least weighted uses is spilled, and
+// spilled intervals that don't overlap share a stack slot.
+pub fn allocate(func: &Function, callee_saved: &[u8], caller_saved: &[u8]) -> Allocation {
+    let intervals = intervals(func);
+    let calls: Vec<usize> = (0..func.instrs.len()).filter(|&i| is_call(&func.instrs[i])).collect();
+
+    let mut locations = HashMap::new();
+    let mut free: Vec<u8> = callee_saved.iter().chain(caller_saved).cloned().collect();
+    let mut active: Vec<(usize, u8)> = Vec::new();
+    let mut spilled = Vec::new();
+    for (i, interval) in intervals.iter().enumerate() {
+        active.retain(|&(j, reg)| {
+            let expired = intervals[j].end < interval.start;
+            if expired {
+                free.push(reg);
+            }
+            !expired
+        });
+        let crosses_call = calls.iter().any(|&p| interval.start < p && p < interval.end);
+        let preferred = if crosses_call { callee_saved } else { caller_saved };
+        let choice = free
+            .iter()
+            .position(|reg| preferred.contains(reg))
+            .or(if free.is_empty() { None } else { Some(0) });
+        if let Some(k) = choice {
+            let reg = free.remove(k);
+            locations.insert(interval.var.clone(), Location::Reg(reg));
+            active.push((i, reg));
+            continue;
+        }
+        let cheapest = active
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| intervals[a.0].weight.partial_cmp(&intervals[b.0].weight).unwrap());
+        match cheapest {
+            Some((k, &(j, reg))) if intervals[j].weight < interval.weight => {
+                active.remove(k);
+                locations.insert(interval.var.clone(), Location::Reg(reg));
+                active.push((i, reg));
+                spilled.push(j);
+            }
+            _ => spilled.push(i),
+        }
+    }
+
+    // spilled intervals overwrite any register they had; each slot remembers
+    // the end of the last interval placed in it
+    let mut slots: Vec<usize> = Vec::new();
+    spilled.sort();
+    for j in spilled {
+        let interval = &intervals[j];
+        let slot = match slots.iter().position(|&end| end < interval.start) {
+            Some(slot) => slot,
+            None => {
+                slots.push(0);
+                slots.len() - 1
+            }
+        };
+        slots[slot] = interval.end;
+        locations.insert(interval.var.clone(), Location::Slot(slot));
+    }
+
+    Allocation {
+        intervals,
+        locations,
+        num_slots: slots.len(),
+    }
+}
@@ -0,0 +1,84 @@
+use serde::Serialize;
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Applied,
+    Missed,
+}
+
+// Why a pass did or didn't transform something. `index` is the position in
+// the instruction list the pass was looking at, which for passes working on
+// SSA form is that of the SSA copy.
+#[derive(Serialize)]
+pub struct Remark {
+    pub pass: &'static str,
+    pub function: String,
+    pub index: Option<usize>,
+    pub kind: Kind,
+    pub reason: String,
+}
+
+thread_local! {
+    // None unless remarks were asked for, so passes don't pay for them
+    static REMARKS: RefCell<Option<Vec<Remark>>> = const { RefCell::new(None) };
+}
+
+pub fn enable() {
+    REMARKS.with(|remarks| *remarks.borrow_mut() = Some(Vec::new()));
+}
+
+pub fn is_enabled() -> bool {
+    REMARKS.with(|remarks| remarks.borrow().is_some())
+}
+
+// Remarks made on one thread, to be handed to another.
+pub fn take() -> Vec<Remark> {
+    REMARKS.with(|remarks| remarks.borrow_mut().as_mut().map(mem::take).unwrap_or_default())
+}
+
+pub fn extend(more: Vec<Remark>) {
+    REMARKS.with(|remarks| {
+        if let Some(remarks) = remarks.borrow_mut().as_mut() {
+            remarks.extend(more);
+        }
+    });
+}
+
+pub fn emit(pass: &'static str, function: &str, index: Option<usize>, kind: Kind, reason: &dyn Fn() -> String) {
+    REMARKS.with(|remarks| {
+        if let Some(remarks) = remarks.borrow_mut().as_mut() {
+            remarks.push(Remark {
+                pass,
+                function: function.to_string(),
+                index,
+                kind,
+                reason: reason(),
+            });
+        }
+    });
+}
+
+pub fn applied(pass: &'static str, function: &str, index: Option<usize>, reason: &dyn Fn() -> String) {
+    emit(pass, function, index, Kind::Applied, reason);
+}
+
+pub fn missed(pass: &'static str, function: &str, index: Option<usize>, reason: &dyn Fn() -> String) {
+    emit(pass, function, index, Kind::Missed, reason);
+}
+
+// One JSON object per line.
+pub fn write(path: &str) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    REMARKS.with(|remarks| {
+        for remark in remarks.borrow().iter().flatten() {
+            writeln!(file, "{}", serde_json::to_string(remark).unwrap())?;
+        }
+        Ok(())
+    })
+}
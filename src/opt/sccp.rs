@@ -0,0 +1,273 @@
+use super::cfg::{self, Cfg};
+use super::remarks;
+use super::ssa;
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Value {
+    Top,
+    Const(i64),
+    Bottom,
+}
+
+fn meet(a: Value, b: Value) -> Value {
+    match (a, b) {
+        (Value::Top, v) | (v, Value::Top) => v,
+        (Value::Const(x), Value::Const(y)) if x == y => a,
+        _ => Value::Bottom,
+    }
+}
+
+struct Sccp<'f> {
+    cfg: &'f Cfg,
+    labels: HashMap<&'f str, usize>,
+    users: HashMap<&'f str, Vec<(usize, usize)>>,
+    values: HashMap<&'f str, Value>,
+    executable: Vec<bool>,
+    edges: HashSet<(usize, usize)>,
+    flow_work: Vec<(usize, usize)>,
+    ssa_work: Vec<(usize, usize)>,
+}
+
+impl<'f> Sccp<'f> {
+    fn new(cfg: &'f Cfg, func: &'f Function) -> Sccp<'f> {
+        let mut labels = HashMap::new();
+        let mut users = HashMap::<&str, Vec<(usize, usize)>>::new();
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            if let Some(label) = &block.label {
+                labels.insert(label.as_str(), b);
+            }
+            for (i, instr) in block.instrs.iter().enumerate() {
+                for arg in uses(instr) {
+                    users.entry(arg).or_default().push((b, i));
+                }
+            }
+        }
+        let mut values = HashMap::new();
+        if let Some(args) = &func.args {
+            for arg in args {
+                values.insert(arg.name.as_str(), Value::Bottom);
+            }
+        }
+
+        Sccp {
+            cfg,
+            labels,
+            users,
+            values,
+            executable: vec![false; cfg.blocks.len()],
+            edges: HashSet::new(),
+            flow_work: Vec::new(),
+            ssa_work: Vec::new(),
+        }
+    }
+
+    fn value(&self, var: &str) -> Value {
+        *self.values.get(var).unwrap_or(&Value::Top)
+    }
+
+    fn run(&mut self) {
+        if self.cfg.blocks.is_empty() {
+            return;
+        }
+        self.visit_block(0, true);
+        loop {
+            if let Some((p, b)) = self.flow_work.pop() {
+                if !self.edges.insert((p, b)) {
+                    continue;
+                }
+                let first = !self.executable[b];
+                self.visit_block(b, first);
+            } else if let Some((b, i)) = self.ssa_work.pop() {
+                if self.executable[b] {
+                    self.visit(b, i);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Phis are revisited for every new incoming edge, everything else only
+    // the first time the block becomes reachable.
+    fn visit_block(&mut self, b: usize, first: bool) {
+        self.executable[b] = true;
+        let instrs = &self.cfg.blocks[b].instrs;
+        for (i, instr) in instrs.iter().enumerate() {
+            if first || instr.op == Some(OpCode::Phi) {
+                self.visit(b, i);
+            }
+        }
+        if first && !instrs.last().is_some_and(is_terminator) {
+            for &s in &self.cfg.succs[b] {
+                self.flow_work.push((b, s));
+            }
+        }
+    }
+
+    fn visit(&mut self, b: usize, i: usize) {
+        let instr = &self.cfg.blocks[b].instrs[i];
+        match (&instr.op, &instr.args) {
+            (Some(OpCode::Br), Some(args)) => {
+                let taken: &[String] = match self.value(&args[0]) {
+                    Value::Const(c) if c != 0 => &args[1..2],
+                    Value::Const(_) => &args[2..3],
+                    Value::Bottom => &args[1..],
+                    Value::Top => &[],
+                };
+                for target in taken {
+                    if let Some(&s) = self.labels.get(target.as_str()) {
+                        self.flow_work.push((b, s));
+                    }
+                }
+            }
+            (Some(OpCode::Jmp), Some(args)) => {
+                if let Some(&s) = self.labels.get(args[0].as_str()) {
+                    self.flow_work.push((b, s));
+                }
+            }
+            _ => {
+                if let Some(dest) = &instr.dest {
+                    let value = meet(self.value(dest), self.evaluate(b, instr));
+                    if value != self.value(dest) {
+                        self.values.insert(dest, value);
+                        if let Some(users) = self.users.get(dest.as_str()) {
+                            self.ssa_work.extend(users);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn evaluate(&self, b: usize, instr: &Instruction) -> Value {
+        let args: &[String] = instr.args.as_ref().map_or(&[], |a| a);
+        match &instr.op {
+            Some(OpCode::Const) => Value::Const(instr.value.unwrap_or(0)),
+            Some(OpCode::Id) => self.value(&args[0]),
+            Some(OpCode::Phi) => {
+                let mut value = Value::Top;
+                if let Some(labels) = &instr.labels {
+                    for (arg, label) in args.iter().zip(labels) {
+                        if let Some(&p) = self.labels.get(label.as_str()) {
+                            if self.edges.contains(&(p, b)) {
+                                value = meet(value, self.value(arg));
+                            }
+                        }
+                    }
+                }
+                value
+            }
+            Some(OpCode::BinOp(op)) | Some(OpCode::BinOpBool(op)) => {
+                match (self.value(&args[0]), self.value(&args[1])) {
+                    (Value::Const(a), Value::Const(b)) => match fold(op, a, b) {
+                        Some(v) => Value::Const(v),
+                        None => Value::Bottom,
+                    },
+                    (Value::Bottom, _) | (_, Value::Bottom) => Value::Bottom,
+                    _ => Value::Top,
+                }
+            }
+            Some(OpCode::UnOpBool(_)) => match self.value(&args[0]) {
+                Value::Const(a) => Value::Const((a == 0) as i64),
+                v => v,
+            },
+            _ => Value::Bottom,
+        }
+    }
+}
+
+// Matches the interpreter's arithmetic, except that division by zero is left
+// in place to trap at run time.
+pub fn fold(op: &str, a: i64, b: i64) -> Option<i64> {
+    match op {
+        "add" => Some(a.wrapping_add(b)),
+        "sub" => Some(a.wrapping_sub(b)),
+        "mul" => Some(a.wrapping_mul(b)),
+        "div" => a.checked_div(b),
+        "shl" => Some(a.wrapping_shl(b as u32)),
+        "shr" => Some(a.wrapping_shr(b as u32)),
+        "eq" => Some((a == b) as i64),
+        "lt" => Some((a < b) as i64),
+        "gt" => Some((a > b) as i64),
+        "le" => Some((a <= b) as i64),
+        "ge" => Some((a >= b) as i64),
+        "and" => Some((a != 0 && b != 0) as i64),
+        "or" => Some((a != 0 || b != 0) as i64),
+        _ => None,
+    }
+}
+
+pub fn run(func: &mut Function) -> bool {
+    let mut ssa_func = func.clone();
+    ssa::to_ssa(&mut ssa_func);
+    let mut cfg = Cfg::new(&ssa_func);
+
+    let (values, executable) = {
+        let mut sccp = Sccp::new(&cfg, &ssa_func);
+        sccp.run();
+        let values: HashMap<String, i64> = sccp
+            .values
+            .iter()
+            .filter_map(|(&var, &value)| match value {
+                Value::Const(c) => Some((var.to_string(), c)),
+                _ => None,
+            })
+            .collect();
+        (values, sccp.executable)
+    };
+
+    let mut changed = false;
+    let starts: Vec<usize> = (0..cfg.blocks.len()).map(|b| cfg::position(&cfg.blocks, b, 0)).collect();
+    for (b, block) in cfg.blocks.iter_mut().enumerate() {
+        if !executable[b] {
+            changed = true;
+            continue;
+        }
+        for (i, instr) in block.instrs.iter_mut().enumerate() {
+            match (&instr.op, &instr.args, &instr.dest) {
+                (Some(OpCode::Const), _, _) => {}
+                (Some(OpCode::Br), Some(args), _) => {
+                    if let Some(&c) = values.get(&args[0]) {
+                        let target = if c != 0 { &args[1] } else { &args[2] };
+                        remarks::applied("sccp", &func.name, Some(starts[b] + i), &|| {
+                            format!("branch on {} always goes to {}", args[0], target)
+                        });
+                        *instr = jmp(target);
+                        changed = true;
+                    }
+                }
+                // only pure instructions and division by a non-zero constant
+                // can have a constant value
+                (_, _, Some(dest)) => {
+                    if let Some(&c) = values.get(dest) {
+                        *instr = Instruction {
+                            op: Some(OpCode::Const),
+                            dest: Some(dest.clone()),
+                            r#type: instr.r#type.clone(),
+                            value: Some(c),
+                            ..Default::default()
+                        };
+                        changed = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if !changed {
+        return false;
+    }
+
+    let mut b = 0;
+    cfg.blocks.retain(|_| {
+        b += 1;
+        executable[b - 1]
+    });
+    ssa_func.instrs = cfg::flatten(cfg.blocks);
+    ssa::from_ssa(&mut ssa_func);
+    *func = ssa_func;
+    true
+}
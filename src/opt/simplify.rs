@@ -0,0 +1,139 @@
+use super::cfg::{self, BasicBlock, Cfg};
+use super::dce;
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+pub fn run(func: &mut Function) -> bool {
+    let mut fresh = Fresh::new(func);
+    let mut blocks = Cfg::new(func).blocks;
+    cfg::make_explicit(&mut blocks, &mut fresh);
+
+    let mut changed = false;
+    loop {
+        let mut progress = fold_branches(&mut blocks);
+        progress |= thread_jumps(&mut blocks);
+        progress |= remove_unreachable(&mut blocks);
+        progress |= merge_blocks(&mut blocks);
+        if !progress {
+            break;
+        }
+        changed = true;
+    }
+
+    // jumps to the next block turn back into falling through, and so does
+    // returning nothing at the very end
+    for i in 1..blocks.len() {
+        let next = blocks[i].label.clone().unwrap();
+        let prev = &mut blocks[i - 1].instrs;
+        if prev.last().is_some_and(|last| last.op == Some(OpCode::Jmp) && targets(last)[0] == next) {
+            prev.pop();
+        }
+    }
+    if let Some(last) = blocks.last_mut() {
+        let returns_nothing = |instr: &Instruction| {
+            instr.op == Some(OpCode::Ret) && instr.args.as_ref().is_none_or(|args| args.is_empty())
+        };
+        if last.instrs.last().is_some_and(returns_nothing) {
+            last.instrs.pop();
+        }
+    }
+    let mut simplified = Function {
+        instrs: cfg::flatten(blocks),
+        ..func.clone()
+    };
+    dce::remove_unused_labels(&mut simplified);
+    if !changed && simplified.instrs.len() == func.instrs.len() {
+        return false;
+    }
+    *func = simplified;
+    true
+}
+
+// br c L L is just jmp L
+fn fold_branches(blocks: &mut [BasicBlock]) -> bool {
+    let mut changed = false;
+    for block in blocks.iter_mut() {
+        let target = match block.instrs.last() {
+            Some(last) if last.op == Some(OpCode::Br) && targets(last)[0] == targets(last)[1] => targets(last)[0].clone(),
+            _ => continue,
+        };
+        *block.instrs.last_mut().unwrap() = jmp(&target);
+        changed = true;
+    }
+    changed
+}
+
+// Jumps to a block that does nothing but jump again go straight to the
+// final destination.
+fn thread_jumps(blocks: &mut [BasicBlock]) -> bool {
+    let forward: HashMap<String, String> = blocks
+        .iter()
+        .filter_map(|block| match &block.instrs[..] {
+            [only] if only.op == Some(OpCode::Jmp) => Some((block.label.clone()?, targets(only)[0].clone())),
+            _ => None,
+        })
+        .collect();
+    let mut changed = false;
+    for block in blocks.iter_mut() {
+        let last = match block.instrs.last_mut() {
+            Some(last) => last,
+            None => continue,
+        };
+        for target in targets(last).to_vec() {
+            let mut seen = HashSet::new();
+            let mut dest = &target;
+            while let Some(next) = forward.get(dest) {
+                if !seen.insert(dest) {
+                    break;
+                }
+                dest = next;
+            }
+            if *dest != target {
+                retarget(last, &target, dest);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+fn remove_unreachable(blocks: &mut Vec<BasicBlock>) -> bool {
+    let cfg = Cfg::from_blocks(mem::take(blocks));
+    let reachable = cfg.reachable();
+    *blocks = cfg.blocks;
+    let len = blocks.len();
+    let mut i = 0;
+    blocks.retain(|_| {
+        i += 1;
+        reachable[i - 1]
+    });
+    blocks.len() != len
+}
+
+// A block whose only predecessor jumps straight to it becomes part of that
+// predecessor.
+fn merge_blocks(blocks: &mut Vec<BasicBlock>) -> bool {
+    let mut changed = false;
+    loop {
+        let cfg = Cfg::from_blocks(mem::take(blocks));
+        let pair = (0..cfg.blocks.len()).find_map(|a| {
+            let last = cfg.blocks[a].instrs.last()?;
+            match cfg.succs[a][..] {
+                [b] if last.op == Some(OpCode::Jmp) && b != a && b != 0 && cfg.preds[b].len() == 1 => Some((a, b)),
+                _ => None,
+            }
+        });
+        *blocks = cfg.blocks;
+        let (a, b) = match pair {
+            Some(pair) => pair,
+            None => return changed,
+        };
+        let tail = mem::take(&mut blocks[b].instrs);
+        blocks[a].instrs.pop();
+        blocks[a].instrs.extend(tail);
+        blocks.remove(b);
+        changed = true;
+    }
+}
@@ -0,0 +1,136 @@
+use super::remarks;
+use super::*;
+
+use std::collections::HashMap;
+
+// Clones cost code size, so only small functions get them and each function
+// gets a limited number.
+pub static MAX_SIZE: usize = 200;
+pub static MAX_CLONES: usize = 8;
+
+// The constant passed for each parameter, if any.
+pub type Signature = Vec<Option<i64>>;
+
+// Variables that hold the same constant at every definition.
+pub fn constants(func: &Function) -> HashMap<String, i64> {
+    let mut consts = HashMap::<String, Option<i64>>::new();
+    if let Some(params) = &func.args {
+        for param in params {
+            consts.insert(param.name.clone(), None);
+        }
+    }
+    for instr in &func.instrs {
+        if let Some(dest) = &instr.dest {
+            let value = match instr.op {
+                Some(OpCode::Const) => instr.value,
+                _ => None,
+            };
+            let entry = consts.entry(dest.clone()).or_insert(value);
+            if *entry != value {
+                *entry = None;
+            }
+        }
+    }
+    consts
+        .into_iter()
+        .filter_map(|(var, value)| Some((var, value?)))
+        .collect()
+}
+
+pub fn signature(call: &Instruction, consts: &HashMap<String, i64>) -> Signature {
+    match &call.args {
+        Some(args) => args[1..].iter().map(|arg| consts.get(arg).cloned()).collect(),
+        None => Vec::new(),
+    }
+}
+
+pub fn is_profitable(callee: &Function, signature: &Signature) -> bool {
+    let arity = callee.args.as_ref().map_or(0, |a| a.len());
+    arity == signature.len()
+        && signature.iter().any(|c| c.is_some())
+        && callee.instrs.len() <= MAX_SIZE
+}
+
+// The clone keeps the full parameter list, so callers pass the same
+// arguments as before; the constant ones are just overwritten on entry.
+pub fn clone(func: &Function, signature: &Signature, name: String) -> Function {
+    let mut instrs = Vec::new();
+    if let Some(params) = &func.args {
+        for (param, value) in params.iter().zip(signature) {
+            if let Some(value) = value {
+                instrs.push(Instruction {
+                    op: Some(OpCode::Const),
+                    dest: Some(param.name.clone()),
+                    value: Some(*value),
+                    r#type: param.r#type.clone(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    instrs.extend(func.instrs.iter().cloned());
+    Function {
+        name,
+        instrs,
+        ..func.clone()
+    }
+}
+
+pub fn run(program: &mut Program) -> bool {
+    let functions: HashMap<String, Function> = program
+        .functions
+        .iter()
+        .map(|f| (f.name.clone(), f.clone()))
+        .collect();
+    let mut clones = HashMap::<(String, Signature), String>::new();
+    let mut num_clones = HashMap::<String, usize>::new();
+    let mut new_funcs = Vec::new();
+
+    for func in &mut program.functions {
+        let consts = constants(func);
+        for (i, instr) in func.instrs.iter_mut().enumerate() {
+            let callee = match (&instr.op, &instr.args) {
+                (Some(OpCode::Call), Some(args)) => match functions.get(&args[0]) {
+                    Some(callee) => callee,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let signature = signature(instr, &consts);
+            if !is_profitable(callee, &signature) {
+                continue;
+            }
+            let key = (callee.name.clone(), signature);
+            let name = match clones.get(&key) {
+                Some(name) => name.clone(),
+                None => {
+                    let count = num_clones.entry(callee.name.clone()).or_insert(0);
+                    if *count == MAX_CLONES {
+                        remarks::missed("spec", &func.name, Some(i), &|| {
+                            format!("{} already has {} clones", callee.name, MAX_CLONES)
+                        });
+                        continue;
+                    }
+                    *count += 1;
+                    let mut suffix = *count;
+                    let mut name = format!("{}.spec.{}", callee.name, suffix);
+                    while functions.contains_key(&name) {
+                        suffix += MAX_CLONES;
+                        name = format!("{}.spec.{}", callee.name, suffix);
+                    }
+                    new_funcs.push(clone(callee, &key.1, name.clone()));
+                    clones.insert(key, name.clone());
+                    name
+                }
+            };
+            remarks::applied("spec", &func.name, Some(i), &|| {
+                format!("call to {} specialized as {}", callee.name, name)
+            });
+            instr.args.as_mut().unwrap()[0] = name;
+        }
+    }
+
+    let changed = !new_funcs.is_empty();
+    program.functions.extend(new_funcs);
+    changed
+}
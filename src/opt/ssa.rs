@@ -0,0 +1,308 @@
+use super::cfg::{self, dominator_tree, BasicBlock, Cfg};
+use super::*;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+// Phi argument for a path on which the variable was never assigned
+pub static UNDEFINED: &str = "__undefined";
+
+enum Visit {
+    Enter(usize),
+    Exit(Vec<String>),
+}
+
+pub fn to_ssa(func: &mut Function) {
+    let mut fresh = Fresh::new(func);
+    let cfg = Cfg::new(func);
+    let reachable = cfg.reachable();
+    let mut blocks: Vec<BasicBlock> = cfg
+        .blocks
+        .into_iter()
+        .zip(reachable)
+        .filter(|(_, r)| *r)
+        .map(|(b, _)| b)
+        .collect();
+    if blocks.is_empty() {
+        return;
+    }
+
+    // phis refer to their predecessors by label, and the arguments need an
+    // entry block that nothing jumps back to
+    for block in &mut blocks {
+        if block.label.is_none() {
+            block.label = Some(fresh.name("b"));
+        }
+    }
+    let mut cfg = Cfg::from_blocks(blocks);
+    if !cfg.preds[0].is_empty() {
+        let mut blocks = cfg.blocks;
+        blocks.insert(
+            0,
+            BasicBlock {
+                label: Some(fresh.name("entry")),
+                instrs: Vec::new(),
+            },
+        );
+        cfg = Cfg::from_blocks(blocks);
+    }
+
+    let idom = cfg.dominators();
+    let frontiers = cfg.dominance_frontiers(&idom);
+    let tree = dominator_tree(&idom);
+    let n = cfg.blocks.len();
+
+    let mut defs = BTreeMap::<String, BTreeSet<usize>>::new();
+    let mut types = HashMap::<String, String>::new();
+    if let Some(args) = &func.args {
+        for arg in args {
+            defs.entry(arg.name.clone()).or_default().insert(0);
+            if let Some(t) = &arg.r#type {
+                types.insert(arg.name.clone(), t.clone());
+            }
+        }
+    }
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for instr in &block.instrs {
+            if let Some(dest) = &instr.dest {
+                defs.entry(dest.clone()).or_default().insert(b);
+                if let Some(t) = &instr.r#type {
+                    types.entry(dest.clone()).or_insert_with(|| t.clone());
+                }
+            }
+        }
+    }
+
+    let mut phis = vec![Vec::<String>::new(); n];
+    for (var, def_blocks) in &defs {
+        let mut work: Vec<usize> = def_blocks.iter().cloned().collect();
+        let mut placed = HashSet::new();
+        while let Some(b) = work.pop() {
+            for &d in &frontiers[b] {
+                if placed.insert(d) {
+                    phis[d].push(var.clone());
+                    if !def_blocks.contains(&d) {
+                        work.push(d);
+                    }
+                }
+            }
+        }
+    }
+
+    let Cfg {
+        mut blocks, succs, ..
+    } = cfg;
+    let mut stacks = HashMap::<String, Vec<String>>::new();
+    if let Some(args) = &func.args {
+        for arg in args {
+            stacks.insert(arg.name.clone(), vec![arg.name.clone()]);
+        }
+    }
+    let mut phi_dests: Vec<Vec<String>> = phis.iter().map(|p| vec![String::new(); p.len()]).collect();
+    let mut phi_args: Vec<Vec<Vec<(String, String)>>> =
+        phis.iter().map(|p| vec![Vec::new(); p.len()]).collect();
+
+    let mut visits = vec![Visit::Enter(0)];
+    while let Some(visit) = visits.pop() {
+        let b = match visit {
+            Visit::Exit(pushed) => {
+                for var in pushed {
+                    stacks.get_mut(&var).unwrap().pop();
+                }
+                continue;
+            }
+            Visit::Enter(b) => b,
+        };
+
+        let mut pushed = Vec::new();
+        for (i, var) in phis[b].iter().enumerate() {
+            let name = fresh.name(var);
+            stacks.entry(var.clone()).or_default().push(name.clone());
+            pushed.push(var.clone());
+            phi_dests[b][i] = name;
+        }
+        for instr in &mut blocks[b].instrs {
+            for arg in uses_mut(instr) {
+                if let Some(name) = stacks.get(arg.as_str()).and_then(|s| s.last()) {
+                    *arg = name.clone();
+                }
+            }
+            if let Some(dest) = &mut instr.dest {
+                let name = fresh.name(dest);
+                stacks.entry(dest.clone()).or_default().push(name.clone());
+                pushed.push(dest.clone());
+                *dest = name;
+            }
+        }
+        let label = blocks[b].label.clone().unwrap();
+        for &s in &succs[b] {
+            for (i, var) in phis[s].iter().enumerate() {
+                let arg = match stacks.get(var).and_then(|s| s.last()) {
+                    Some(name) => name.clone(),
+                    None => UNDEFINED.to_string(),
+                };
+                phi_args[s][i].push((label.clone(), arg));
+            }
+        }
+
+        visits.push(Visit::Exit(pushed));
+        for &child in tree[b].iter().rev() {
+            visits.push(Visit::Enter(child));
+        }
+    }
+
+    for (b, block) in blocks.iter_mut().enumerate() {
+        let mut instrs = Vec::new();
+        for (i, var) in phis[b].iter().enumerate() {
+            let (labels, args): (Vec<String>, Vec<String>) = phi_args[b][i].drain(..).unzip();
+            instrs.push(Instruction {
+                op: Some(OpCode::Phi),
+                dest: Some(phi_dests[b][i].clone()),
+                r#type: types.get(var).cloned(),
+                args: Some(args),
+                labels: Some(labels),
+                ..Default::default()
+            });
+        }
+        instrs.append(&mut block.instrs);
+        block.instrs = instrs;
+    }
+    func.instrs = cfg::flatten(blocks);
+}
+
+pub fn from_ssa(func: &mut Function) {
+    let mut fresh = Fresh::new(func);
+    let Cfg {
+        mut blocks,
+        succs,
+        preds,
+    } = Cfg::new(func);
+
+    let live = live_phis(&blocks);
+    let mut edge_copies = Vec::new();
+    for b in 0..blocks.len() {
+        let phis: Vec<Instruction> = blocks[b]
+            .instrs
+            .iter()
+            .filter(|instr| is_phi(instr) && live.contains(instr.dest.as_ref().unwrap()))
+            .cloned()
+            .collect();
+        blocks[b].instrs.retain(|instr| !is_phi(instr));
+        for &p in &preds[b] {
+            let mut copies = Vec::new();
+            for phi in &phis {
+                if let (Some(dest), Some(args), Some(labels)) = (&phi.dest, &phi.args, &phi.labels) {
+                    let arg = labels
+                        .iter()
+                        .position(|l| Some(l) == blocks[p].label.as_ref())
+                        .map(|k| &args[k]);
+                    if let Some(arg) = arg {
+                        copies.push((dest.clone(), arg.clone(), phi.r#type.clone()));
+                    }
+                }
+            }
+            if !copies.is_empty() {
+                edge_copies.push((p, b, copies));
+            }
+        }
+    }
+
+    // copies on a critical edge get a block of their own
+    let mut edge_blocks = Vec::new();
+    for (p, b, copies) in edge_copies {
+        let mut instrs = sequentialize(copies, &mut fresh);
+        if succs[p].len() > 1 {
+            let name = fresh.name("edge");
+            let target = blocks[b].label.clone().unwrap();
+            if let Some(last) = blocks[p].instrs.last_mut() {
+                retarget(last, &target, &name);
+            }
+            instrs.push(jmp(&target));
+            edge_blocks.push(BasicBlock {
+                label: Some(name),
+                instrs,
+            });
+        } else {
+            blocks[p].push_before_terminator(instrs);
+        }
+    }
+
+    if !edge_blocks.is_empty() {
+        if let Some(last) = blocks.last_mut() {
+            if !last.instrs.last().is_some_and(is_terminator) {
+                last.instrs.push(Instruction {
+                    op: Some(OpCode::Ret),
+                    args: Some(Vec::new()),
+                    ..Default::default()
+                });
+            }
+        }
+        blocks.extend(edge_blocks);
+    }
+    func.instrs = cfg::flatten(blocks);
+}
+
+pub fn is_phi(instr: &Instruction) -> bool {
+    instr.op == Some(OpCode::Phi)
+}
+
+// Phis that only feed other phis would turn into copies nobody reads.
+fn live_phis(blocks: &[BasicBlock]) -> HashSet<String> {
+    let mut phis = HashMap::<&str, &Instruction>::new();
+    let mut work = Vec::new();
+    for instr in blocks.iter().flat_map(|b| b.instrs.iter()) {
+        if is_phi(instr) {
+            phis.insert(instr.dest.as_ref().unwrap(), instr);
+        } else {
+            work.extend(uses(instr).iter().map(|a| a.as_str()));
+        }
+    }
+    let mut live = HashSet::new();
+    while let Some(var) = work.pop() {
+        if let Some(phi) = phis.get(var) {
+            if live.insert(var.to_string()) {
+                work.extend(uses(phi).iter().map(|a| a.as_str()));
+            }
+        }
+    }
+    live
+}
+
+// A variable that is unassigned on some path still gets a value there, so
+// copies out of later phis never read an undefined variable. Correct
+// programs can't observe it.
+fn copy(dest: &str, src: &str, r#type: Option<String>) -> Instruction {
+    if src == UNDEFINED {
+        Instruction {
+            op: Some(OpCode::Const),
+            dest: Some(dest.to_string()),
+            r#type,
+            value: Some(0),
+            ..Default::default()
+        }
+    } else {
+        id(dest, src, r#type)
+    }
+}
+
+// Phi copies on one edge happen in parallel, so a copy that reads another
+// phi's destination has to go through a temporary.
+fn sequentialize(copies: Vec<(String, String, Option<String>)>, fresh: &mut Fresh) -> Vec<Instruction> {
+    let dests: HashSet<&str> = copies.iter().map(|(d, _, _)| d.as_str()).collect();
+    let conflict = copies
+        .iter()
+        .any(|(d, s, _)| d != s && dests.contains(s.as_str()));
+    if !conflict {
+        return copies.iter().map(|(d, s, t)| copy(d, s, t.clone())).collect();
+    }
+    let mut instrs = Vec::new();
+    let mut temps = Vec::new();
+    for (d, s, t) in &copies {
+        let tmp = fresh.name(d);
+        instrs.push(copy(&tmp, s, t.clone()));
+        temps.push(tmp);
+    }
+    for ((d, _, t), tmp) in copies.iter().zip(temps) {
+        instrs.push(id(d, &tmp, t.clone()));
+    }
+    instrs
+}
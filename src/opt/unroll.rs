@@ -0,0 +1,431 @@
+use super::cfg::{self, BasicBlock, Cfg, Loop};
+use super::remarks;
+use super::sccp::fold;
+use super::specialize;
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+
+pub struct UnrollConfig {
+    // copies of the body in a partially unrolled loop
+    pub factor: usize,
+    // instructions the function may grow by
+    pub budget: usize,
+}
+
+impl Default for UnrollConfig {
+    fn default() -> UnrollConfig {
+        UnrollConfig {
+            factor: 4,
+            budget: 256,
+        }
+    }
+}
+
+// A loop whose header is just `cond = op var bound; br cond body exit`, with
+// `var` stepped by a constant exactly once per iteration and `bound`
+// invariant.
+struct Counted {
+    var: String,
+    step: i64,
+    op: String,
+    bound: String,
+    body: String,
+    exit: String,
+}
+
+pub fn run(func: &mut Function, config: &UnrollConfig) -> bool {
+    let mut fresh = Fresh::new(func);
+    // copies only ever repeat definitions, so these stay valid throughout
+    let consts = specialize::constants(func);
+    let params: Vec<&str> = func.args.iter().flatten().map(|p| p.name.as_str()).collect();
+    let mut blocks = Cfg::new(func).blocks;
+    cfg::make_explicit(&mut blocks, &mut fresh);
+    let mut budget = config.budget;
+    let mut changed = false;
+
+    // Only innermost loops are unrolled. A fully unrolled loop can make its
+    // parent innermost, so this repeats until nothing is left to try.
+    let mut visited = HashSet::<String>::new();
+    loop {
+        let cfg = Cfg::from_blocks(blocks);
+        let loops = cfg.natural_loops();
+        let next = loops
+            .iter()
+            .filter(|l| !visited.contains(cfg.blocks[l.header].label.as_ref().unwrap()))
+            .filter(|l| loops.iter().all(|o| o.header == l.header || !l.body.contains(&o.header)))
+            .min_by_key(|l| l.body.len());
+        let lp = match next {
+            Some(lp) => lp,
+            None => {
+                blocks = cfg.blocks;
+                break;
+            }
+        };
+        let header = cfg.blocks[lp.header].label.clone().unwrap();
+        let at = Some(cfg::position(&cfg.blocks, lp.header, 0));
+        visited.insert(header.clone());
+        let counted = match counted(&cfg, lp, &consts) {
+            Some(counted) => counted,
+            None => {
+                remarks::missed("unroll", &func.name, at, &|| {
+                    format!("loop at {} is not a counted loop", header)
+                });
+                blocks = cfg.blocks;
+                continue;
+            }
+        };
+
+        let size: usize = lp
+            .body
+            .iter()
+            .filter(|&&b| b != lp.header)
+            .map(|&b| cfg.blocks[b].instrs.len())
+            .sum();
+        let trip_count = if params.contains(&counted.var.as_str()) {
+            None
+        } else {
+            trip_count(&cfg, lp, &counted, &consts, budget / size.max(1))
+        };
+        blocks = if let Some(n) = trip_count {
+            remarks::applied("unroll", &func.name, at, &|| {
+                format!("fully unrolled loop at {} ({} iterations)", header, n)
+            });
+            budget -= n * size;
+            fully_unroll(cfg, lp, &counted, n, &mut fresh)
+        } else if config.factor > 1 && config.factor * size <= budget {
+            // how far the guard looks ahead, which has to fit itself
+            let offset = match counted.step.checked_mul(config.factor as i64 - 1) {
+                Some(offset) => offset,
+                None => {
+                    remarks::missed("unroll", &func.name, at, &|| {
+                        format!("loop at {} steps too far to unroll", header)
+                    });
+                    blocks = cfg.blocks;
+                    continue;
+                }
+            };
+            remarks::applied("unroll", &func.name, at, &|| {
+                format!("unrolled loop at {} by {}", header, config.factor)
+            });
+            budget -= config.factor * size;
+            let (blocks, remainder) = partially_unroll(cfg, lp, &counted, config.factor, offset, &mut fresh);
+            visited.insert(remainder);
+            blocks
+        } else {
+            remarks::missed("unroll", &func.name, at, &|| {
+                format!("loop at {} exceeds the remaining budget of {}", header, budget)
+            });
+            blocks = cfg.blocks;
+            continue;
+        };
+        changed = true;
+    }
+    if changed {
+        func.instrs = cfg::flatten(blocks);
+    }
+    changed
+}
+
+fn counted(cfg: &Cfg, lp: &Loop, consts: &HashMap<String, i64>) -> Option<Counted> {
+    let blocks = &cfg.blocks;
+    let header = &blocks[lp.header];
+    if header.instrs.len() != 2 {
+        return None;
+    }
+    let (cmp, br) = (&header.instrs[0], &header.instrs[1]);
+    let cond = cmp.dest.as_ref()?;
+    let op = match &cmp.op {
+        Some(OpCode::BinOp(op)) if ["lt", "le", "gt", "ge"].contains(&op.as_str()) => op.clone(),
+        _ => return None,
+    };
+    let br_args = br.args.as_ref()?;
+    if br.op != Some(OpCode::Br) || br_args[0] != *cond {
+        return None;
+    }
+    let index = |label: &str| blocks.iter().position(|b| b.label.as_ref().is_some_and(|l| l == label));
+    let (body, exit) = (index(&br_args[1])?, index(&br_args[2])?);
+    if body == lp.header || !lp.body.contains(&body) || lp.body.contains(&exit) {
+        return None;
+    }
+
+    // only the header test may leave the loop
+    for &b in lp.body.iter().filter(|&&b| b != lp.header) {
+        let ends_in_ret = blocks[b].instrs.last().is_some_and(|i| i.op == Some(OpCode::Ret));
+        if ends_in_ret || cfg.succs[b].is_empty() || cfg.succs[b].iter().any(|s| !lp.body.contains(s)) {
+            return None;
+        }
+    }
+
+    let mut defs = HashMap::<&str, Vec<(usize, &Instruction)>>::new();
+    for &b in &lp.body {
+        for instr in &blocks[b].instrs {
+            if let Some(dest) = &instr.dest {
+                defs.entry(dest).or_default().push((b, instr));
+            }
+        }
+    }
+    let cmp_args = cmp.args.as_ref()?;
+    let (var, bound, op) = if defs.contains_key(cmp_args[0].as_str()) {
+        (&cmp_args[0], &cmp_args[1], op)
+    } else {
+        let flipped = match op.as_str() {
+            "lt" => "gt",
+            "le" => "ge",
+            "gt" => "lt",
+            _ => "le",
+        };
+        (&cmp_args[1], &cmp_args[0], flipped.to_string())
+    };
+    if defs.contains_key(bound.as_str()) {
+        return None;
+    }
+
+    // the single step has to run on every path around the loop
+    let (at, step) = match &defs.get(var.as_str())?[..] {
+        [(b, instr)] if instr.op == Some(OpCode::BinOp("add".to_string())) => (*b, *instr),
+        _ => return None,
+    };
+    let idom = cfg.dominators();
+    let latches = cfg.preds[lp.header].iter().filter(|p| lp.body.contains(p));
+    if !latches.into_iter().all(|&l| cfg::dominates(&idom, at, l)) {
+        return None;
+    }
+    let step_args = step.args.as_ref()?;
+    let step = match (&step_args[0], &step_args[1]) {
+        (a, s) | (s, a) if a == var && consts.contains_key(s) => consts[s],
+        _ => return None,
+    };
+    let increasing = op == "lt" || op == "le";
+    if step == 0 || (step > 0) != increasing {
+        return None;
+    }
+
+    // the condition is only known to hold while the header runs
+    let cond_used = lp
+        .body
+        .iter()
+        .flat_map(|&b| blocks[b].instrs.iter())
+        .filter(|instr| !std::ptr::eq(*instr, br))
+        .any(|instr| uses(instr).contains(cond));
+    if cond_used {
+        return None;
+    }
+
+    Some(Counted {
+        var: var.clone(),
+        step,
+        op,
+        bound: bound.clone(),
+        body: br_args[1].clone(),
+        exit: br_args[2].clone(),
+    })
+}
+
+// The loop runs a known number of times when it can only be entered once and
+// both its start and bound are constants.
+fn trip_count(
+    cfg: &Cfg,
+    lp: &Loop,
+    counted: &Counted,
+    consts: &HashMap<String, i64>,
+    limit: usize,
+) -> Option<usize> {
+    let mut seen = vec![false; cfg.blocks.len()];
+    let mut stack: Vec<usize> = cfg.succs[lp.header]
+        .iter()
+        .cloned()
+        .filter(|s| !lp.body.contains(s))
+        .collect();
+    while let Some(b) = stack.pop() {
+        if b == lp.header {
+            return None;
+        }
+        if !seen[b] {
+            seen[b] = true;
+            stack.extend(&cfg.succs[b]);
+        }
+    }
+
+    let mut start = None;
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for instr in &block.instrs {
+            if instr.dest.as_ref() != Some(&counted.var) || lp.body.contains(&b) {
+                continue;
+            }
+            match (&instr.op, start) {
+                (Some(OpCode::Const), None) => start = instr.value,
+                (Some(OpCode::Const), Some(v)) if instr.value == Some(v) => {}
+                _ => return None,
+            }
+        }
+    }
+    let mut i = start?;
+    let bound = *consts.get(&counted.bound)?;
+
+    let mut n = 0;
+    while fold(&counted.op, i, bound)? != 0 {
+        n += 1;
+        if n > limit {
+            return None;
+        }
+        i = i.checked_add(counted.step)?;
+    }
+    Some(n)
+}
+
+// Copies the loop body without its header. Jumps back to the header go to
+// `next` instead. Returns the copy and the label of its first block.
+fn copy_body(blocks: &[BasicBlock], lp: &Loop, counted: &Counted, next: &str, fresh: &mut Fresh) -> (Vec<BasicBlock>, String) {
+    let header = blocks[lp.header].label.as_ref().unwrap();
+    let mut names = HashMap::<&str, String>::new();
+    for &b in lp.body.iter().filter(|&&b| b != lp.header) {
+        let label = blocks[b].label.as_ref().unwrap();
+        names.insert(label, fresh.name(label));
+    }
+    let mut copy = Vec::new();
+    for &b in lp.body.iter().filter(|&&b| b != lp.header) {
+        let mut instrs = blocks[b].instrs.clone();
+        if let Some(last) = instrs.last_mut() {
+            retarget(last, header, next);
+            for (from, to) in &names {
+                retarget(last, from, to);
+            }
+        }
+        copy.push(BasicBlock {
+            label: Some(names[blocks[b].label.as_ref().unwrap().as_str()].clone()),
+            instrs,
+        });
+    }
+    let entry = names[counted.body.as_str()].clone();
+    (copy, entry)
+}
+
+// Copies chained so that each one's back edges enter the next, the last one
+// continuing at `next`.
+fn chain(blocks: &[BasicBlock], lp: &Loop, counted: &Counted, copies: usize, next: String, fresh: &mut Fresh) -> (Vec<BasicBlock>, String) {
+    let mut chained = Vec::new();
+    let mut next = next;
+    for _ in 0..copies {
+        let (copy, entry) = copy_body(blocks, lp, counted, &next, fresh);
+        chained.push(copy);
+        next = entry;
+    }
+    chained.reverse();
+    (chained.into_iter().flatten().collect(), next)
+}
+
+// Inserts `new` where the loop header was and leaves out the other loop
+// blocks if `drop_loop` is set.
+fn splice(blocks: Vec<BasicBlock>, lp: &Loop, new: Vec<BasicBlock>, drop_loop: bool) -> Vec<BasicBlock> {
+    let mut result = Vec::new();
+    let mut new = Some(new);
+    for (b, block) in blocks.into_iter().enumerate() {
+        if b == lp.header {
+            result.extend(new.take().unwrap());
+        }
+        if !drop_loop || !lp.body.contains(&b) {
+            result.push(block);
+        }
+    }
+    result
+}
+
+fn fully_unroll(cfg: Cfg, lp: &Loop, counted: &Counted, n: usize, fresh: &mut Fresh) -> Vec<BasicBlock> {
+    let blocks = cfg.blocks;
+    let header = blocks[lp.header].label.clone().unwrap();
+    // the last test still runs, since its result may be read after the loop
+    let last = BasicBlock {
+        label: Some(fresh.name(&header)),
+        instrs: vec![blocks[lp.header].instrs[0].clone(), jmp(&counted.exit)],
+    };
+    let (mut new, entry) = chain(&blocks, lp, counted, n, last.label.clone().unwrap(), fresh);
+    new.insert(
+        0,
+        BasicBlock {
+            label: Some(header),
+            instrs: vec![jmp(&entry)],
+        },
+    );
+    new.push(last);
+    splice(blocks, lp, new, true)
+}
+
+// The unrolled loop keeps going while all `factor` iterations are known to
+// run, and the original loop takes care of the rest. That's when the last of
+// them, `offset` past var, passes the test, which is asked as whether var
+// passes it against bound - offset so that var + offset can't wrap. Where
+// bound - offset wraps instead, it lands on the wrong side of bound and the
+// original loop runs.
+fn partially_unroll(
+    cfg: Cfg,
+    lp: &Loop,
+    counted: &Counted,
+    factor: usize,
+    offset: i64,
+    fresh: &mut Fresh,
+) -> (Vec<BasicBlock>, String) {
+    let mut blocks = cfg.blocks;
+    let header = blocks[lp.header].label.clone().unwrap();
+    let remainder = fresh.name(&header);
+    let (copies, entry) = chain(&blocks, lp, counted, factor, header.clone(), fresh);
+
+    let (offset_var, limit) = (fresh.name("unroll.offset"), fresh.name("unroll.limit"));
+    let (in_range, fits, cond) = (fresh.name("unroll.in_range"), fresh.name("unroll.fits"), fresh.name("unroll.cond"));
+    let toward = if offset > 0 { "lt" } else { "gt" };
+    let guard = BasicBlock {
+        label: Some(header.clone()),
+        instrs: vec![
+            Instruction {
+                op: Some(OpCode::Const),
+                dest: Some(offset_var.clone()),
+                value: Some(offset),
+                r#type: Some("int".to_string()),
+                ..Default::default()
+            },
+            Instruction {
+                op: Some(OpCode::BinOp("sub".to_string())),
+                dest: Some(limit.clone()),
+                args: Some(vec![counted.bound.clone(), offset_var]),
+                r#type: Some("int".to_string()),
+                ..Default::default()
+            },
+            Instruction {
+                op: Some(OpCode::BinOp(counted.op.clone())),
+                dest: Some(in_range.clone()),
+                args: Some(vec![counted.var.clone(), limit.clone()]),
+                r#type: Some("bool".to_string()),
+                ..Default::default()
+            },
+            Instruction {
+                op: Some(OpCode::BinOp(toward.to_string())),
+                dest: Some(fits.clone()),
+                args: Some(vec![limit, counted.bound.clone()]),
+                r#type: Some("bool".to_string()),
+                ..Default::default()
+            },
+            Instruction {
+                op: Some(OpCode::BinOpBool("and".to_string())),
+                dest: Some(cond.clone()),
+                args: Some(vec![in_range, fits]),
+                r#type: Some("bool".to_string()),
+                ..Default::default()
+            },
+            Instruction {
+                op: Some(OpCode::Br),
+                args: Some(vec![cond, entry, remainder.clone()]),
+                ..Default::default()
+            },
+        ],
+    };
+
+    blocks[lp.header].label = Some(remainder.clone());
+    for &b in &lp.body {
+        if let Some(last) = blocks[b].instrs.last_mut() {
+            retarget(last, &header, &remainder);
+        }
+    }
+    let mut new = vec![guard];
+    new.extend(copies);
+    (splice(blocks, lp, new, false), remainder)
+}
@@ -0,0 +1,75 @@
+use super::*;
+
+use std::collections::HashSet;
+
+pub fn program(program: &Program) -> Result<(), String> {
+    for func in &program.functions {
+        function(func, program)?;
+    }
+    Ok(())
+}
+
+// Checks the invariants every pass may rely on: operand counts, labels that
+// exist and are unique, known callees with matching arity, and variables that
+// are defined somewhere in the function. Phis are only allowed inside passes.
+pub fn function(func: &Function, program: &Program) -> Result<(), String> {
+    let mut labels = HashSet::<&str>::new();
+    let mut defined = HashSet::<&str>::new();
+    if let Some(params) = &func.args {
+        defined.extend(params.iter().map(|p| p.name.as_str()));
+    }
+    for instr in &func.instrs {
+        if let Some(label) = &instr.label {
+            if !labels.insert(label) {
+                return Err(format!("{}: duplicate label {}", func.name, label));
+            }
+        }
+        if let Some(dest) = &instr.dest {
+            defined.insert(dest);
+        }
+    }
+
+    for (i, instr) in func.instrs.iter().enumerate() {
+        let error = |msg: String| Err(format!("{}: instruction {}: {}", func.name, i, msg));
+        let op = match &instr.op {
+            Some(op) => op,
+            None => continue,
+        };
+        let num_args = instr.args.as_ref().map_or(0, |args| args.len());
+        let (arity_ok, needs_dest) = match op {
+            OpCode::BinOp(_) | OpCode::BinOpBool(_) => (num_args == 2, true),
+            OpCode::UnOpBool(_) | OpCode::Id => (num_args == 1, true),
+            OpCode::Const => (num_args == 0 && instr.value.is_some(), true),
+            OpCode::Jmp => (num_args == 1, false),
+            OpCode::Br => (num_args == 3, false),
+            OpCode::Ret => (num_args <= 1, false),
+            OpCode::Call => (num_args >= 1, false),
+            OpCode::Print | OpCode::Nop => (true, false),
+            OpCode::Phi => return error("phi outside of SSA form".to_string()),
+        };
+        if !arity_ok {
+            return error(format!("malformed {}", String::from(op.clone())));
+        }
+        if needs_dest && instr.dest.is_none() {
+            return error(format!("{} without a destination", String::from(op.clone())));
+        }
+        if let Some(var) = uses(instr).iter().find(|v| !defined.contains(v.as_str())) {
+            return error(format!("use of undefined variable {}", var));
+        }
+        if let Some(target) = targets(instr).iter().find(|l| !labels.contains(l.as_str())) {
+            return error(format!("jump to missing label {}", target));
+        }
+        if *op == OpCode::Call {
+            let args = instr.args.as_ref().unwrap();
+            match program.functions.iter().find(|f| f.name == args[0]) {
+                Some(callee) => {
+                    if callee.args.as_ref().map_or(0, |a| a.len()) + 1 != args.len() {
+                        return error(format!("wrong number of arguments to {}", args[0]));
+                    }
+                }
+                None => return error(format!("call to unknown function {}", args[0])),
+            }
+        }
+    }
+    Ok(())
+}
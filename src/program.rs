@@ -4,8 +4,8 @@ use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
 
-#[derive(Clone, Deserialize, Debug, Serialize)]
-#[serde(from = "String")]
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
+#[serde(from = "String", into = "String")]
 pub enum OpCode {
     BinOp(String),
     BinOpBool(String),
@@ -18,6 +18,7 @@ pub enum OpCode {
     Ret,
     Id,
     UnOpBool(String),
+    Phi,
 }
 
 #[derive(Clone, Deserialize, Debug, Serialize)]
@@ -31,14 +32,18 @@ pub struct Function {
     pub args: Option<Vec<Param>>,
     pub instrs: Vec<Instruction>,
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct Param {
-    pub name: String
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
 }
 
-#[derive(Clone, Deserialize, Debug, Serialize)]
+#[derive(Clone, Default, Deserialize, Debug, Serialize)]
 pub struct Instruction {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
@@ -52,13 +57,17 @@ pub struct Instruction {
     pub value: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub r#type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
 }
 
 impl From<String> for OpCode {
   fn from(op: String) -> Self {
     match op.as_ref() {
       "nop" => OpCode::Nop,
-      "add" | "mul" | "sub" | "div" | "eq" | "lt" | "gt" | "le" | "ge" => OpCode::BinOp(op),
+      "add" | "mul" | "sub" | "div" | "shl" | "shr" | "eq" | "lt" | "gt" | "le" | "ge" => {
+        OpCode::BinOp(op)
+      }
       "and" | "or" => OpCode::BinOpBool(op),
       "not" => OpCode::UnOpBool(op),
       "const" => OpCode::Const,
@@ -68,6 +77,7 @@ impl From<String> for OpCode {
       "id" => OpCode::Id,
       "print" => OpCode::Print,
       "call" => OpCode::Call,
+      "phi" => OpCode::Phi,
       _ => {
         println!("Unknown instruction, treating as NOP");
         OpCode::Nop
@@ -76,6 +86,42 @@ impl From<String> for OpCode {
   }
 }
 
+impl From<OpCode> for String {
+  fn from(op: OpCode) -> Self {
+    match op {
+      OpCode::BinOp(op) | OpCode::BinOpBool(op) | OpCode::UnOpBool(op) => op,
+      OpCode::Call => "call".to_string(),
+      OpCode::Const => "const".to_string(),
+      OpCode::Nop => "nop".to_string(),
+      OpCode::Print => "print".to_string(),
+      OpCode::Jmp => "jmp".to_string(),
+      OpCode::Br => "br".to_string(),
+      OpCode::Ret => "ret".to_string(),
+      OpCode::Id => "id".to_string(),
+      OpCode::Phi => "phi".to_string(),
+    }
+  }
+}
+
+impl Function {
+  // A call whose result is immediately returned can hand its caller's frame
+  // over to the callee. Returns the callee's name if the call at `i` is one.
+  pub fn tail_call(&self, i: usize) -> Option<&str> {
+    let call = &self.instrs[i];
+    let ret = self.instrs.get(i + 1)?;
+    if call.op != Some(OpCode::Call) || ret.op != Some(OpCode::Ret) {
+      return None;
+    }
+    // a call with no result followed by a bare ret isn't one, since the
+    // callee may still return a value the caller mustn't
+    let returned = ret.args.as_ref().and_then(|args| args.first());
+    match (&call.dest, returned) {
+      (Some(dest), Some(returned)) if dest == returned => {}
+      _ => return None,
+    }
+    call.args.as_ref().map(|args| args[0].as_str())
+  }
+}
 
 pub fn read_json(file_name: &str) -> Result<Program, Box<dyn Error>> {
   let prog_file = File::open(file_name)?;
@@ -0,0 +1,264 @@
+use super::opt::manager::PassManager;
+use super::program::*;
+
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Arguments for main are drawn from these, small enough that loops bounded
+// by them finish quickly.
+static VALUES: [i64; 8] = [0, 1, 2, 3, 5, 8, 13, -1];
+static NUM_INPUTS: usize = 8;
+// Runs are cut off at this, or at SLOWDOWN times the unoptimized run if that
+// is longer, so a pass that makes a program slower isn't mistaken for one
+// that makes it loop forever.
+static TIMEOUT: Duration = Duration::from_secs(10);
+static SLOWDOWN: u32 = 10;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Engine {
+    Interpreter,
+    Jit,
+}
+
+impl Engine {
+    fn name(&self) -> &'static str {
+        match self {
+            Engine::Interpreter => "interpreter",
+            Engine::Jit => "jit",
+        }
+    }
+}
+
+// What a run of the program looked like from the outside. Main's return
+// value is the last line of stdout (see -ret).
+enum Outcome {
+    Exited(String),
+    Failed(String, Failure, String),
+    TimedOut(Duration),
+}
+
+// How a run failed. A division the interpreter panics on traps in jitted
+// code instead, so the two count as the same failure.
+#[derive(PartialEq)]
+enum Failure {
+    Division,
+    Exit(i32),
+    Signal(i32),
+    Spawn,
+}
+
+impl Failure {
+    fn new(status: ExitStatus, stderr: &str) -> Failure {
+        if stderr.contains("attempt to divide") || status.signal() == Some(libc::SIGFPE) {
+            Failure::Division
+        } else if let Some(signal) = status.signal() {
+            Failure::Signal(signal)
+        } else {
+            Failure::Exit(status.code().unwrap_or(-1))
+        }
+    }
+}
+
+// Runs that fail agree if they failed the same way after printing the same
+// lines: a trap loses whatever wasn't flushed.
+impl PartialEq for Outcome {
+    fn eq(&self, other: &Outcome) -> bool {
+        let lines = |stdout: &str| stdout.rfind('\n').map_or(0, |end| end + 1);
+        match (self, other) {
+            (Outcome::Exited(a), Outcome::Exited(b)) => a == b,
+            (Outcome::Failed(a, x, _), Outcome::Failed(b, y, _)) => x == y && a[..lines(a)] == b[..lines(b)],
+            (Outcome::TimedOut(_), Outcome::TimedOut(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Outcome {
+    fn describe(&self) -> String {
+        match self {
+            Outcome::Exited(stdout) => format!("{:?}", stdout),
+            Outcome::Failed(stdout, _, status) => format!("{:?}, then failed ({})", stdout, status),
+            Outcome::TimedOut(limit) => format!("timed out after {}s", limit.as_secs()),
+        }
+    }
+}
+
+fn inputs(main: &Function) -> Vec<Vec<i64>> {
+    let params = match &main.args {
+        Some(params) if !params.is_empty() => params,
+        _ => return vec![Vec::new()],
+    };
+    (0..NUM_INPUTS)
+        .map(|k| {
+            params
+                .iter()
+                .enumerate()
+                .map(|(j, param)| match param.r#type.as_deref() {
+                    Some("bool") => ((k + j) % 2) as i64,
+                    _ => VALUES[(k + 3 * j) % VALUES.len()],
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Runs the program on the interpreter and the JIT after every pass of the
+// pipeline, comparing against the interpreter on the unoptimized program.
+// Returns whether everything agreed.
+pub fn run(file_name: &str, program: &Program, pipeline: &PassManager) -> bool {
+    let main = match program.functions.iter().find(|func| func.name == "main") {
+        Some(main) => main,
+        None => {
+            eprintln!("validate: no main function");
+            return false;
+        }
+    };
+    let stages = match pipeline.stages(program) {
+        Ok(stages) => stages,
+        Err(e) => {
+            eprintln!("validate: {}", e);
+            return false;
+        }
+    };
+
+    // stage 0 is the program as given
+    let mut files = vec![PathBuf::from(file_name)];
+    for (k, (_, stage)) in stages.iter().enumerate() {
+        match write_stage(stage, k + 1) {
+            Ok(path) => files.push(path),
+            Err(e) => {
+                eprintln!("validate: couldn't write stage {}: {}", k + 1, e);
+                remove_stages(&files);
+                return false;
+            }
+        }
+    }
+
+    let inputs = inputs(main);
+    eprintln!("validate: {} passes, {} inputs", stages.len(), inputs.len());
+    let mut ok = true;
+    for input in &inputs {
+        let start = Instant::now();
+        let expected = execute(&files[0], Engine::Interpreter, input, TIMEOUT);
+        if let Outcome::TimedOut(_) = expected {
+            eprintln!("input {:?}: skipped, the unoptimized program timed out", input);
+            continue;
+        }
+        let limit = TIMEOUT.max(start.elapsed() * SLOWDOWN);
+        for &engine in &[Engine::Interpreter, Engine::Jit] {
+            // most runs agree, so only walk the stages when the last one doesn't
+            if execute(files.last().unwrap(), engine, input, limit) == expected {
+                continue;
+            }
+            ok = false;
+            for (k, file) in files.iter().enumerate() {
+                let got = execute(file, engine, input, limit);
+                if got == expected {
+                    continue;
+                }
+                if k == 0 {
+                    eprintln!(
+                        "input {:?}: {} disagrees with the interpreter before any pass ran",
+                        input,
+                        engine.name()
+                    );
+                } else {
+                    eprintln!(
+                        "input {:?}: {} diverges after pass {} ({} of {})",
+                        input,
+                        engine.name(),
+                        stages[k - 1].0,
+                        k,
+                        stages.len()
+                    );
+                }
+                eprintln!("  expected: {}", expected.describe());
+                eprintln!("  got:      {}", got.describe());
+                break;
+            }
+        }
+    }
+    remove_stages(&files);
+    if ok {
+        eprintln!("validate: ok");
+    }
+    ok
+}
+
+fn write_stage(program: &Program, k: usize) -> io::Result<PathBuf> {
+    let path = env::temp_dir().join(format!("jit-bril-validate-{}-{}.json", std::process::id(), k));
+    let file = File::create(&path)?;
+    serde_json::to_writer(file, program)?;
+    Ok(path)
+}
+
+fn remove_stages(files: &[PathBuf]) {
+    for path in &files[1..] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+// Each run is a child process, so a crash in jitted code shows up as a
+// result instead of taking the validator down with it.
+fn execute(file: &Path, engine: Engine, input: &[i64], limit: Duration) -> Outcome {
+    let output = env::temp_dir().join(format!("jit-bril-validate-{}.out", std::process::id()));
+    let errors = env::temp_dir().join(format!("jit-bril-validate-{}.err", std::process::id()));
+    let result = spawn_and_wait(file, engine, input, &output, &errors, limit);
+    let outcome = match result {
+        Ok(Some(status)) if status.success() => {
+            Outcome::Exited(fs::read_to_string(&output).unwrap_or_default())
+        }
+        Ok(Some(status)) => {
+            let stderr = fs::read_to_string(&errors).unwrap_or_default();
+            Outcome::Failed(
+                fs::read_to_string(&output).unwrap_or_default(),
+                Failure::new(status, &stderr),
+                status.to_string(),
+            )
+        }
+        Ok(None) => Outcome::TimedOut(limit),
+        Err(e) => Outcome::Failed(String::new(), Failure::Spawn, e.to_string()),
+    };
+    let _ = fs::remove_file(&output);
+    let _ = fs::remove_file(&errors);
+    outcome
+}
+
+fn spawn_and_wait(
+    file: &Path,
+    engine: Engine,
+    input: &[i64],
+    output: &Path,
+    errors: &Path,
+    limit: Duration,
+) -> io::Result<Option<ExitStatus>> {
+    let mut command = Command::new(env::current_exe()?);
+    // stages are already optimized, so the JIT must not optimize them again
+    command.arg(file).arg("-O0").arg("-ret");
+    if engine == Engine::Jit {
+        command.arg("-jit").arg("0");
+    }
+    command.args(input.iter().map(|arg| arg.to_string()));
+    let mut child = command
+        .stdout(File::create(output)?)
+        .stderr(File::create(errors)?)
+        .spawn()?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() > limit {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
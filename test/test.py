@@ -1,6 +1,14 @@
 import argparse
 import glob, os
 import subprocess
+import tempfile
+
+# Flags each mode runs a test with. A test with a <name>.flags file runs
+# once for every line of it instead, an empty line being no flags at all and
+# a line starting with `validate` running that subcommand. A line starting
+# with `compile` builds an executable in the test's directory and runs that.
+# `{dir}` in a line is that directory, which all of the test's runs share.
+MODES = {"interp": "", "jit": "-jit 0", "compile": "compile"}
 
 def baseline(json):
     p1 = subprocess.Popen(["cat", json], stdout=subprocess.PIPE)
@@ -11,44 +19,76 @@ def baseline(json):
     f.write("{}".format(int(output)))
     f.close()
 
-def interpreter(json):
-    file_name = os.path.splitext(json)[0]
-    print("test {} ...".format(file_name), end=" ")
-    p1 = subprocess.Popen(["cat", json], stdout=subprocess.PIPE)
-    p2 = subprocess.Popen(["../target/release/jit-bril", "interp"], stdin=p1.stdout, stdout=subprocess.PIPE)
-    p1.stdout.close()
-    output, err = p2.communicate()
-    baseline = open("{}.txt".format(file_name), 'r')
-    baseline_values = baseline.read()
-    interpreter_output = str(output).split("\\n")[1].strip()
-    if (str(baseline_values) == interpreter_output):
-        print("ok")
+def lines(text):
+    return [line.rstrip() for line in text.strip().split("\n")]
+
+def run(json, flags, dir, remarks):
+    args = flags.replace("{dir}", dir).split()
+    if remarks is not None:
+        args.append("--remarks={}".format(remarks))
+    if args and args[0] == "compile":
+        exe = os.path.join(dir, "a.out")
+        p = subprocess.run(["../target/release/jit-bril", "compile", json, "-o", exe] + args[1:])
+        if p.returncode != 0:
+            return p.returncode, ""
+        p = subprocess.run([exe], stdout=subprocess.PIPE)
+    elif args and args[0] == "validate":
+        p = subprocess.run(["../target/release/jit-bril", "validate", json] + args[1:], stdout=subprocess.PIPE)
     else:
-        print("FAILED. Expected {}, Got {}".format(baseline_values, interpreter_output))
+        p = subprocess.run(["../target/release/jit-bril", json] + args, stdout=subprocess.PIPE)
+    return p.returncode, p.stdout.decode()
 
-def jit(json):
+def test(json, mode):
     file_name = os.path.splitext(json)[0]
     print("test {} ...".format(file_name), end=" ")
-    p1 = subprocess.Popen(["cat", json], stdout=subprocess.PIPE)
-    p2 = subprocess.Popen(["../target/release/jit-bril", "jit"], stdin=p1.stdout, stdout=subprocess.PIPE)
-    p1.stdout.close()
-    output, err = p2.communicate()
-    baseline = open("{}.txt".format(file_name), 'r')
-    baseline_values = baseline.read()
-    interpreter_output = str(output).split("\\n")[1].strip()
-    if (str(baseline_values) == interpreter_output):
-        print("ok")
-    else:
-        print("FAILED. Expected {}, Got {}".format(baseline_values, interpreter_output))
+    expected = lines(open("{}.txt".format(file_name)).read())
+    flags = [MODES[mode]]
+    if os.path.exists("{}.flags".format(file_name)):
+        flags = open("{}.flags".format(file_name)).read().rstrip("\n").split("\n")
+    # every line of <name>.remarks has to turn up in what the last run wrote
+    wanted = []
+    if os.path.exists("{}.remarks".format(file_name)):
+        wanted = open("{}.remarks".format(file_name)).read().strip().split("\n")
+    with tempfile.TemporaryDirectory() as dir:
+        remarks = os.path.join(dir, "remarks") if wanted else None
+        for line in flags:
+            status, output = run(json, line, dir, remarks)
+            if status != 0:
+                print("FAILED with `{}`. Exited with {}".format(line, status))
+                return False
+            if lines(output) != expected:
+                print("FAILED with `{}`. Expected {}, Got {}".format(line, expected, lines(output)))
+                return False
+        for remark in wanted:
+            if remark not in open(remarks).read():
+                print("FAILED. No remark {}".format(remark))
+                return False
+    print("ok")
+    return True
+
+def interpreter(json):
+    return test(json, "interp")
+
+def jit(json):
+    return test(json, "jit")
+
+def compiled(json):
+    return test(json, "compile")
 
 if __name__ == "__main__":
     parser = argparse.ArgumentParser()
-    parser.add_argument('--mode', help='mode to test, interp or jit', required=True)
+    parser.add_argument('--mode', help='mode to test, interp, jit or compile', required=True)
     args = parser.parse_args()
     mode = args.mode
-    print("running {} tests".format(len(glob.glob("./unit/*.json"))))
-    for file in glob.glob("./unit/*.json"):
+    files = sorted(glob.glob("./unit/*.json"))
+    print("running {} tests".format(len(files)))
+    failed = 0
+    for file in files:
         if mode == "interp":
-            interpreter(file)
+            failed += not interpreter(file)
         elif mode == "jit":
-            jit(file)
\ No newline at end of file
+            failed += not jit(file)
+        elif mode == "compile":
+            failed += not compiled(file)
+    if failed:
+        exit(1)